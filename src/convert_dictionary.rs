@@ -1,11 +1,74 @@
-use std::{io, cmp::Ordering, path::PathBuf, time::SystemTime, collections::HashMap};
+use std::{fmt, io};
 
-use bytes::BytesMut;
-use terminus_store::{storage::{PersistentLayerStore, archive::ArchiveLayerStore, consts::{self, LayerFileEnum, FILENAME_ENUM_MAP}, FileLoad, string_to_name}, structure::{TypedDict, Datatype, TypedDictBufBuilder, TdbDataType, LangString, TypedDictEntry}};
+use terminus_store::{storage::{PersistentLayerStore, consts, FileLoad, FileStore}, structure::{TypedDict, Datatype, TdbDataType, LangString, TypedDictEntry}};
 
-use crate::dataconversion::prolog_string_to_string;
+use thiserror::Error;
 
-pub async fn convert_value_dict(in_store: &ArchiveLayerStore, out_store: &ArchiveLayerStore, id: [u32;5], offset: u64) -> io::Result<(HashMap<u64, u64>, u64)> {
+use crate::dict_file_builder::TypedDictFileBuilder;
+use crate::external_sort::{ExternalSorter, ScratchDir};
+use crate::dataconversion::{prolog_string_to_string_with_policy, string_to_prolog_string, EscapeDecodeError, MalformedEscapePolicy};
+use crate::lang_string::{lang_string_to_prolog_lang_string, parse_prolog_lang_string, LangStringParseError};
+use crate::observer::{ConversionObserver, LayerProgress};
+
+#[derive(Debug, Error)]
+pub enum DictionaryConversionError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("failed to decode value {id}: {source}")]
+    EscapeDecode { id: u64, source: EscapeDecodeError },
+
+    #[error("failed to parse langString value {id}: {source}")]
+    LangStringParse { id: u64, source: LangStringParseError },
+}
+
+/// A malformed escape sequence that was handled by the fallback
+/// policy rather than failing the conversion.
+#[derive(Debug)]
+pub struct EscapeFallback {
+    pub id: u64,
+    pub policy: MalformedEscapePolicy,
+    pub error: EscapeDecodeError,
+}
+
+impl EscapeFallback {
+    /// The memory taken by this fallback, including its text.
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.error.sequence.len() + self.error.snippet.len()
+    }
+}
+
+impl fmt::Display for EscapeFallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "applied {} fallback to value {}: {}", self.policy, self.id, self.error)
+    }
+}
+
+pub struct ValueDictConversion {
+    pub old_num_entries: u64,
+    pub new_num_entries: u64,
+    /// The new index of every old entry, if the entries changed order
+    /// or were collapsed.
+    pub reordering: Option<Vec<u64>>,
+    /// The number of entries that were collapsed into another entry.
+    pub duplicates: u64,
+    pub fallbacks: Vec<EscapeFallback>,
+}
+
+/// What converting a value dictionary would do, without writing it.
+#[derive(Debug, Default)]
+pub struct ValueDictAnalysis {
+    pub num_entries: u64,
+    /// The number of entries whose text changes.
+    pub changed: u64,
+    /// The number of entries that end up at another index.
+    pub moved: u64,
+    /// The number of entries that would collapse into another entry.
+    pub duplicates: u64,
+    pub fallbacks: u64,
+}
+
+async fn load_value_dict<S: PersistentLayerStore>(in_store: &S, id: [u32;5]) -> io::Result<TypedDict> {
     let types_present_file = in_store.get_file(id, consts::FILENAMES.value_dictionary_types_present).await?;
     let type_offsets_file = in_store.get_file(id, consts::FILENAMES.value_dictionary_type_offsets).await?;
     let blocks_file = in_store.get_file(id, consts::FILENAMES.value_dictionary_blocks).await?;
@@ -16,95 +79,201 @@ pub async fn convert_value_dict(in_store: &ArchiveLayerStore, out_store: &Archiv
     let blocks_map = blocks_file.map().await?;
     let offsets_map = offsets_file.map().await?;
 
-    let dict = TypedDict::from_parts(types_present_map,
-                                     type_offsets_map,
-                                     offsets_map,
-                                     blocks_map);
-
-    let mut new_entries: Vec<(TypedDictEntry, u64)> = Vec::with_capacity(dict.num_entries());
-    let mut reorder = false;
-    for (ix, entry) in dict.into_iter().enumerate().map(|(ix,e)|(ix as u64+offset,e)) {
-        let next_entry;
-        match entry.datatype() {
-            Datatype::String|
-            Datatype::NCName|
-            Datatype::Name|
-            Datatype::Token|
-            Datatype::NMToken|
-            Datatype::NormalizedString|
-            Datatype::Language|
-            Datatype::AnyURI|
-            Datatype::Notation|
-            Datatype::QName|
-            Datatype::ID|
-            Datatype::IDRef|
-            Datatype::Entity|
-            Datatype::AnySimpleType => {
-                let s = entry.as_val::<String, String>();
-                let converted = prolog_string_to_string(&s);
-                next_entry = String::make_entry(&converted);
-            },Datatype::LangString => {
-                let s: String = entry.as_val::<LangString, String>();
-                let pos = s.find('@').expect("no @ found in langstring");
-
-                let mut lang = &s[..pos];
-                if &lang[0..1] == "\'" || &lang[0..1] == "\"" {
-                    lang = &lang[1..lang.len()-1];
-                }
-
-                let val = &s[pos+2..s.len()-1];
-                let string_converted = prolog_string_to_string(val);
-
-                let mut converted = String::with_capacity(s.len());
-                converted.push_str(lang);
-                converted.push('@');
-                converted.push_str(&string_converted);
-
-                next_entry = LangString::make_entry(&converted);
-            },
-            _ => {
-                next_entry = entry;
-            }
+    Ok(TypedDict::from_parts(types_present_map,
+                             type_offsets_map,
+                             offsets_map,
+                             blocks_map))
+}
+
+/// Convert a single value dictionary entry. Returns the converted
+/// entry and whether it differs from the original. Malformed escape
+/// sequences handled by the fallback policy are pushed onto `errors`.
+pub fn convert_entry(ix: u64, entry: TypedDictEntry, policy: MalformedEscapePolicy, reverse: bool, errors: &mut Vec<EscapeDecodeError>) -> Result<(TypedDictEntry, bool), DictionaryConversionError> {
+    let result = match entry.datatype() {
+        Datatype::String|
+        Datatype::NCName|
+        Datatype::Name|
+        Datatype::Token|
+        Datatype::NMToken|
+        Datatype::NormalizedString|
+        Datatype::Language|
+        Datatype::AnyURI|
+        Datatype::Notation|
+        Datatype::QName|
+        Datatype::ID|
+        Datatype::IDRef|
+        Datatype::Entity|
+        Datatype::AnySimpleType => {
+            let s = entry.as_val::<String, String>();
+            let converted = if reverse {
+                string_to_prolog_string(&s)
+            } else {
+                prolog_string_to_string_with_policy(&s, policy, errors)
+                    .map_err(|source| DictionaryConversionError::EscapeDecode { id: ix, source })?
+            };
+            (String::make_entry(&converted), converted != s)
+        },Datatype::LangString if reverse => {
+            let s: String = entry.as_val::<LangString, String>();
+            let pos = s.find('@').ok_or_else(|| DictionaryConversionError::LangStringParse {
+                id: ix,
+                source: LangStringParseError::MissingSeparator(s.clone()),
+            })?;
+            let converted = lang_string_to_prolog_lang_string(&s[..pos], &s[pos+1..]);
+
+            (LangString::make_entry(&converted), converted != s)
+        },Datatype::LangString => {
+            let s: String = entry.as_val::<LangString, String>();
+            let (lang, val) = parse_prolog_lang_string(&s)
+                .map_err(|source| DictionaryConversionError::LangStringParse { id: ix, source })?;
+            // a quoted language tag can have escapes of its own
+            let lang = prolog_string_to_string_with_policy(&lang, policy, errors)
+                .map_err(|source| DictionaryConversionError::EscapeDecode { id: ix, source })?;
+            let string_converted = prolog_string_to_string_with_policy(&val, policy, errors)
+                .map_err(|source| DictionaryConversionError::EscapeDecode { id: ix, source })?;
+
+            let mut converted = String::with_capacity(s.len());
+            converted.push_str(&lang);
+            converted.push('@');
+            converted.push_str(&string_converted);
+
+            (LangString::make_entry(&converted), converted != s)
+        },
+        _ => (entry, false),
+    };
+
+    Ok(result)
+}
+
+/// Work out what [`convert_value_dict`] would do to the value
+/// dictionary of a layer, without writing anything. Entries are sorted
+/// the way [`convert_value_dict`] sorts them, so the only files written
+/// are the sorted runs in the workdir, which are removed again.
+pub async fn analyze_value_dict<S: PersistentLayerStore>(in_store: &S, work: &str, memory_limit: usize, id: [u32;5], policy: MalformedEscapePolicy, reverse: bool) -> Result<ValueDictAnalysis, DictionaryConversionError> {
+    let dict = load_value_dict(in_store, id).await?;
+    let num_entries = dict.num_entries() as u64;
+    let scratch = ScratchDir::for_dry_run(work, id);
+    let mut sorter = ExternalSorter::new(&scratch, memory_limit);
+    let mut changed = 0;
+    let mut fallbacks = 0;
+    let mut errors = Vec::new();
+    for (ix, entry) in dict.into_iter().enumerate().map(|(ix,e)|(ix as u64,e)) {
+        let (next_entry, entry_changed) = convert_entry(ix, entry, policy, reverse, &mut errors)?;
+        if entry_changed {
+            changed += 1;
         }
+        fallbacks += errors.drain(..).count() as u64;
+        sorter.push((next_entry, ix))?;
+    }
 
-        if let Some((last,_)) = new_entries.last() {
-            match last.cmp(&next_entry) {
-                Ordering::Equal => panic!("after normalizing prolog strings, two entries were equal"),
-                Ordering::Greater => {
-                    reorder = true},
-                _ => {}
+    let mut moved = 0;
+    let mut duplicates = 0;
+    if !sorter.is_in_order() {
+        let mut previous: Option<TypedDictEntry> = None;
+        let mut new_num_entries = 0;
+        for entry in sorter.finish()? {
+            let (entry, old_ix) = entry?;
+            if previous.as_ref() == Some(&entry) {
+                duplicates += 1;
+            } else {
+                previous = Some(entry);
+                new_num_entries += 1;
+            }
+            if old_ix != new_num_entries - 1 {
+                moved += 1;
             }
         }
-        new_entries.push((next_entry, ix));
     }
 
+    Ok(ValueDictAnalysis {
+        num_entries,
+        changed,
+        moved,
+        duplicates,
+        fallbacks,
+    })
+}
 
-    let reordered_ids: HashMap<u64, u64>;
-    if reorder {
-        // yikes, the order changed, we'll have to do a lot of work
-        eprintln!(" reordering..");
-        new_entries.sort();
+/// Convert the value dictionary of a layer, writing the converted
+/// dictionary into `out_store`. If the conversion changes the order of
+/// the entries, they are sorted again, spilling sorted runs to the
+/// workdir when they take more than `memory_limit` bytes. The new
+/// index of every old entry and the fallbacks count against that limit
+/// too, as do `reserved` bytes that the caller holds meanwhile.
+#[allow(clippy::too_many_arguments)]
+pub async fn convert_value_dict<F: PersistentLayerStore, T: PersistentLayerStore>(in_store: &F, out_store: &T, work: &str, memory_limit: usize, reserved: usize, id: [u32;5], policy: MalformedEscapePolicy, reverse: bool, observer: &dyn ConversionObserver) -> Result<ValueDictConversion, DictionaryConversionError> {
+    let dict = load_value_dict(in_store, id).await?;
+    let old_num_entries = dict.num_entries() as u64;
+    let scratch = ScratchDir::for_layer(work, id);
+    let mut sorter = ExternalSorter::new(&scratch, memory_limit);
+    sorter.reserve(reserved);
+    // whether the entries have to be reordered is only known once
+    // every entry is pushed, but the reordering is allocated while the
+    // last entries are still buffered.
+    sorter.reserve(old_num_entries as usize * std::mem::size_of::<u64>());
+    let mut fallbacks = Vec::new();
+    let mut errors = Vec::new();
+    for (ix, entry) in dict.into_iter().enumerate().map(|(ix,e)|(ix as u64,e)) {
+        let (next_entry, _) = convert_entry(ix, entry, policy, reverse, &mut errors)?;
+        for error in errors.drain(..) {
+            let fallback = EscapeFallback { id: ix, policy, error };
+            sorter.reserve(fallback.memory());
+            fallbacks.push(fallback);
+        }
+        sorter.push((next_entry, ix))?;
+    }
 
-        reordered_ids = new_entries.iter().enumerate().map(|(new_id, (_, old_id))| (*old_id + offset, new_id as u64)).collect();
+    let mut reordering = if sorter.is_in_order() {
+        None
     } else {
-        reordered_ids = (offset..(offset+new_entries.len() as u64)).map(|ix|(ix,ix)).collect();
+        // yikes, the order changed or entries collapsed, we'll have to do a lot of work
+        observer.layer_progress(id, LayerProgress::Reordering);
+        Some(vec![0; old_num_entries as usize])
+    };
+    if sorter.runs() != 0 {
+        observer.layer_progress(id, LayerProgress::MergingRuns(sorter.runs()));
     }
+    let entries = sorter.finish()?;
 
-    let new_offset = offset + new_entries.len() as u64;
-
-    let mut new_types_present_map = BytesMut::new();
-    let mut new_type_offsets_map = BytesMut::new();
-    let mut new_offsets_map = BytesMut::new();
-    let mut new_blocks_map = BytesMut::new();
-
-    let mut builder =TypedDictBufBuilder::new(&mut new_types_present_map, &mut new_type_offsets_map, &mut new_offsets_map, &mut new_blocks_map);
-    builder.add_all(new_entries.into_iter().map(|(e,_)|e));
+    let mut builder = TypedDictFileBuilder::new(
+        &scratch,
+        open_write(out_store, id, consts::FILENAMES.value_dictionary_types_present).await?,
+        open_write(out_store, id, consts::FILENAMES.value_dictionary_type_offsets).await?,
+        open_write(out_store, id, consts::FILENAMES.value_dictionary_offsets).await?,
+        open_write(out_store, id, consts::FILENAMES.value_dictionary_blocks).await?,
+    )?;
+    // an entry is only added once we know the next one is not a
+    // duplicate of it.
+    let mut pending: Option<TypedDictEntry> = None;
+    let mut new_num_entries = 0;
+    let mut duplicates = 0;
+    for entry in entries {
+        let (entry, old_ix) = entry?;
+        if pending.as_ref() == Some(&entry) {
+            duplicates += 1;
+        } else {
+            if let Some(previous) = pending.replace(entry) {
+                builder.add(previous).await?;
+            }
+            new_num_entries += 1;
+        }
+        if let Some(reordering) = reordering.as_mut() {
+            reordering[old_ix as usize] = new_num_entries - 1;
+        }
+    }
+    if let Some(last) = pending {
+        builder.add(last).await?;
+    }
+    builder.finalize().await?;
 
-    builder.finalize();
-    out_store.write_bytes(id, LayerFileEnum::ValueDictionaryTypesPresent, new_types_present_map.freeze());
-    out_store.write_bytes(id, LayerFileEnum::ValueDictionaryTypeOffsets, new_type_offsets_map.freeze());
-    out_store.write_bytes(id, LayerFileEnum::ValueDictionaryOffsets, new_offsets_map.freeze());
-    out_store.write_bytes(id, LayerFileEnum::ValueDictionaryBlocks, new_blocks_map.freeze());
+    Ok(ValueDictConversion {
+        old_num_entries,
+        new_num_entries,
+        reordering,
+        duplicates,
+        fallbacks,
+    })
+}
 
-    Ok((reordered_ids, new_offset))
+async fn open_write<S: PersistentLayerStore>(store: &S, id: [u32;5], file: &str) -> io::Result<<S::File as FileStore>::Write> {
+    store.get_file(id, file).await?.open_write().await
 }
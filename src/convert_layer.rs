@@ -1,52 +1,173 @@
+use terminus_store::layer::builder::build_indexes;
 use terminus_store::layer::builder::build_object_index;
+use terminus_store::layer::builder::TripleFileBuilder;
+use terminus_store::layer::IdMap;
+use terminus_store::layer::IdTriple;
+use terminus_store::structure::build_bitindex;
+use terminus_store::structure::build_wavelet_tree_from_iter;
+use terminus_store::structure::util::calculate_width;
+use terminus_store::structure::StringDict;
+use terminus_store::structure::TypedDict;
+use terminus_store::storage::FileStore;
+use terminus_store::storage::SyncableFile;
 use terminus_store::storage::AdjacencyListFiles;
 use terminus_store::storage::BitIndexFiles;
 use terminus_store::storage::FileLoad;
 use terminus_store::storage::LayerStore;
 use terminus_store::storage::PersistentLayerStore;
 use terminus_store::storage::archive::ArchiveLayerStore;
+use terminus_store::storage::directory::DirectoryLayerStore;
 use terminus_store::storage::consts::FILENAMES;
 use terminus_store::storage::consts::FILENAME_ENUM_MAP;
 use terminus_store::storage::name_to_string;
 use terminus_store::storage::string_to_name;
-use tokio::io::AsyncReadExt;
 
-use crate::conversion_consts::BASE_INDEX_FILES;
-use crate::conversion_consts::CHILD_INDEX_FILES;
-use crate::conversion_consts::UNCHANGED_FILES;
-use crate::convert_dictionary::convert_value_dict;
+use crate::conversion_consts::{UNCHANGED_FILES, VALUE_DICTIONARY_FILES};
+use crate::convert_dictionary::{convert_value_dict, DictionaryConversionError, EscapeFallback, ValueDictConversion};
+use crate::convert_store::{
+    check_source_version, get_status_hashmap, ConversionOptions, ConversionStatus,
+    StoreConversionError,
+};
+use crate::dataconversion::MalformedEscapePolicy;
 use crate::convert_triples::*;
+use crate::external_sort::{ExternalSorter, ScratchDir};
+use crate::id_mapping::IdMapping;
+use crate::layout::StoreLayout;
+use crate::observer::{ConversionObserver, LayerAction, LayerProgress, Removal};
+use crate::staging::StagedArchiveLayerStore;
 
-use std::collections::HashMap;
 use std::io;
 use std::path::PathBuf;
 
 use bytes::Bytes;
 
-use serde::{Deserialize, Serialize};
-
 use tokio::io::AsyncWriteExt;
 
 use thiserror::Error;
 
+/// Convert a single layer of the store at `from` into the store at
+/// `to`, replacing what was converted of it before. As in a store
+/// conversion, a layer created after the cutoff is copied instead. Its
+/// parent has to be converted already. The layer is built next to the
+/// store, and only replaces the old layer once it is complete, so a
+/// conversion that fails leaves the old layer in place.
 pub async fn convert_layer(
     from: &str,
     to: &str,
-    work: &str,
-    verbose: bool,
+    options: &ConversionOptions,
     id_string: &str,
-) -> Result<(), LayerConversionError> {
-    let from_store = ArchiveLayerStore::new(from);
-    let to_store = ArchiveLayerStore::new(to);
-    let id = string_to_name(id_string).unwrap();
+) -> Result<Vec<EscapeFallback>, StoreConversionError> {
+    let id = string_to_name(id_string)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let observer = options.observer_or_default();
+    check_source_version(from, options.reverse, options.force, &*observer).await?;
+    let staging = staging_path(to);
+    match options.layout {
+        StoreLayout::Archive => {
+            let from_store = ArchiveLayerStore::new(from);
+            // layers are built in the workdir rather than in memory
+            let staging_store = StagedArchiveLayerStore::new(&staging, &options.work);
+            let to_store = StagedArchiveLayerStore::new(to, &options.work);
+            convert_single_layer(from, to, &from_store, &staging_store, &to_store, options, id).await
+        }
+        StoreLayout::Directory => {
+            let from_store = DirectoryLayerStore::new(from);
+            let staging_store = DirectoryLayerStore::new(&staging);
+            let to_store = DirectoryLayerStore::new(to);
+            convert_single_layer(from, to, &from_store, &staging_store, &to_store, options, id).await
+        }
+    }
+}
+
+/// The store a single layer is built in before it replaces the layer
+/// in the store at `to`. It is inside that store, so that the layer
+/// can be moved into place rather than copied.
+fn staging_path(to: &str) -> String {
+    let mut path = PathBuf::from(to);
+    path.push(".convert-layer");
+    path.to_string_lossy().into_owned()
+}
+
+/// Remove the staging store of `to`, and the layer directories that
+/// an archive layer is built in. A staging store that does not exist is
+/// not an error.
+async fn remove_staging(to: &str, work: &str, id: [u32; 5]) -> io::Result<()> {
+    StoreLayout::Directory.remove_layer(work, id).await?;
+    match tokio::fs::remove_dir_all(staging_path(to)).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+async fn convert_single_layer<F: PersistentLayerStore, S: PersistentLayerStore, T: PersistentLayerStore>(
+    from: &str,
+    to: &str,
+    from_store: &F,
+    staging_store: &S,
+    to_store: &T,
+    options: &ConversionOptions,
+    id: [u32; 5],
+) -> Result<Vec<EscapeFallback>, StoreConversionError> {
+    let observer = options.observer_or_default();
+    // left behind by an earlier conversion of the layer that failed
+    remove_staging(to, &options.work, id).await?;
+    // every layer of a converted store was written after the cutoff,
+    // so when reversing, every layer is converted.
+    let is_copied = !options.reverse
+        && options.creation_times_or_default(from).creation_time(id).await? > options.cutoff;
+    let converted = if is_copied {
+        observer.layer_started(id, LayerAction::Copy);
+        copy_layer_with_stores(
+            from_store,
+            staging_store,
+            &options.work,
+            options.memory_limit,
+            &*observer,
+            id,
+            None,
+        )
+        .await
+        .map(|()| Vec::new())
+    } else {
+        observer.layer_started(id, LayerAction::Convert);
+        convert_layer_with_stores(
+            from_store,
+            staging_store,
+            &options.work,
+            options.memory_limit,
+            &*observer,
+            options.policy,
+            options.reverse,
+            id,
+            None,
+        )
+        .await
+    };
+    let fallbacks = match converted {
+        Ok(fallbacks) => fallbacks,
+        Err(e) => {
+            remove_staging(to, &options.work, id).await?;
+            return Err(e.into());
+        }
+    };
+    observer.removed(Removal::Layer(id));
+    options.layout.replace_layer(&staging_path(to), to, id).await?;
+    remove_staging(to, &options.work, id).await?;
 
-    convert_layer_with_stores(&from_store, &to_store, work, verbose, id).await
+    let status = get_status_hashmap(&options.work).await?;
+    copy_rollup_file(from_store, to_store, id, |layer| {
+        layer == id || matches!(status.get(&layer), Some(ConversionStatus::Completed))
+    })
+    .await?;
+    observer.layer_finished(id, &fallbacks);
+
+    Ok(fallbacks)
 }
 
 #[derive(Debug, Error)]
 pub enum InnerLayerConversionError {
-    //#[error(transparent)]
-    //DictionaryConversion(#[from] DictionaryConversionError),
+    #[error(transparent)]
+    DictionaryConversion(#[from] DictionaryConversionError),
     #[error("layer was already converted")]
     LayerAlreadyConverted,
 
@@ -65,7 +186,6 @@ pub enum InnerLayerConversionError {
     #[error("failed to finalize layer: {0}")]
     FinalizationError(io::Error),
 
-    #[allow(unused)]
     #[error("failed to copy rollup file: {0}")]
     RollupFileCopyError(io::Error),
 
@@ -75,8 +195,11 @@ pub enum InnerLayerConversionError {
     #[error(transparent)]
     Io(#[from] io::Error),
 
-    #[error("a nodevalue remap file exists but was not expected")]
-    NodeValueRemapExists,
+    #[error("failed to write the node/value id map: {0}")]
+    IdMapWriteError(io::Error),
+
+    #[error("node/value id map does not match the converted layer {}", name_to_string(*.0))]
+    IdMapMismatch([u32; 5]),
 }
 
 #[derive(Debug, Error)]
@@ -95,14 +218,21 @@ impl LayerConversionError {
     }
 }
 
-pub async fn convert_layer_with_stores(
-    from_store: &ArchiveLayerStore,
-    to_store: &ArchiveLayerStore,
+/// Convert a single layer. If the layer is a rollup, `rollup_of` is
+/// the layer it rolls up, which has to be converted already, as the
+/// rollup has to use the same ids.
+#[allow(clippy::too_many_arguments)]
+pub async fn convert_layer_with_stores<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
     work: &str,
-    verbose: bool,
+    memory_limit: usize,
+    observer: &dyn ConversionObserver,
+    policy: MalformedEscapePolicy,
+    reverse: bool,
     id: [u32; 5],
-) -> Result<(), LayerConversionError> {
-    println!("converting layer {}", name_to_string(id));
+    rollup_of: Option<[u32; 5]>,
+) -> Result<Vec<EscapeFallback>, LayerConversionError> {
     let is_child = PersistentLayerStore::layer_has_parent(from_store, id)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
@@ -121,71 +251,274 @@ pub async fn convert_layer_with_stores(
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
 
-    assert_no_remap_exists(from_store, id)
+    let mapping = get_mapping(work, from_store, id)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
-
-    let map;
-    let offset;
-    let (mut mapping, offset_1) = get_mapping_and_offset(work, from_store, id)
+    observer.layer_progress(id, LayerProgress::ParentMapRetrieved);
+    let node_count = node_dictionary_count(from_store, id)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
-    if verbose {
-        println!("parent mappings retrieved");
-    }
-    let (mapping_addition, offset_1) = convert_value_dict(from_store, to_store, id, offset_1)
+    let values = convert_value_dict(
+        from_store,
+        to_store,
+        work,
+        memory_limit,
+        mapping.memory(),
+        id,
+        policy,
+        reverse,
+        observer,
+    )
+    .await
+    .map_err(|e| LayerConversionError::new(id, e))?;
+    observer.layer_progress(id, LayerProgress::DictionariesConverted);
+
+    remap_layer(
+        from_store,
+        to_store,
+        work,
+        memory_limit,
+        observer,
+        id,
+        rollup_of,
+        is_child,
+        mapping,
+        node_count,
+        values,
+    )
+    .await
+}
+
+/// Write the triples, indexes and id map of a layer whose value
+/// dictionary was written already, with every id mapped to its new id,
+/// and write its parent map.
+#[allow(clippy::too_many_arguments)]
+async fn remap_layer<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
+    work: &str,
+    memory_limit: usize,
+    observer: &dyn ConversionObserver,
+    id: [u32; 5],
+    rollup_of: Option<[u32; 5]>,
+    is_child: bool,
+    mut mapping: IdMapping,
+    node_count: u64,
+    values: ValueDictConversion,
+) -> Result<Vec<EscapeFallback>, LayerConversionError> {
+    let ValueDictConversion {
+        old_num_entries,
+        new_num_entries,
+        reordering,
+        duplicates,
+        fallbacks,
+    } = values;
+    let idmap = read_node_value_idmap(from_store, id, node_count + old_num_entries)
         .await
         .map_err(|e| LayerConversionError::new(id, e))?;
-    mapping.extend(mapping_addition);
-    if verbose {
-        println!("dictionaries converted");
-    }
-    convert_triples(from_store, to_store, id, is_child, &mapping)
+    let has_idmap = idmap.is_some();
+    let mapping = match idmap {
+        None => {
+            mapping.push_layer(
+                node_count,
+                old_num_entries,
+                new_num_entries,
+                reordering.as_deref(),
+            );
+            mapping
+        }
+        Some(idmap) => {
+            let mapping = convert_node_value_idmap(
+                to_store,
+                work,
+                id,
+                rollup_of,
+                mapping,
+                &idmap,
+                node_count,
+                new_num_entries,
+                reordering.as_deref(),
+            )
+            .await
+            .map_err(|e| LayerConversionError::new(id, e))?;
+            observer.layer_progress(id, LayerProgress::IdMapConverted);
+
+            mapping
+        }
+    };
+    // a base layer with an id map is a rollup, whose subjects are
+    // laid out by id, so its triples have to be rebuilt from scratch.
+    let rebuild_triples = !is_child && has_idmap;
+    let removed_triples = if rebuild_triples {
+        rebuild_base_triples(
+            from_store,
+            to_store,
+            work,
+            memory_limit,
+            id,
+            node_count,
+            new_num_entries,
+            &mapping,
+        )
         .await
-        .map_err(|e| {
-            LayerConversionError::new(id, InnerLayerConversionError::TripleConversionError(e))
-        })?;
-    if verbose {
-        println!("triples converted");
+    } else {
+        convert_triples(from_store, to_store, id, is_child, &mapping).await
+    }
+    .map_err(|e| {
+        LayerConversionError::new(id, InnerLayerConversionError::TripleConversionError(e))
+    })?;
+    observer.layer_progress(id, LayerProgress::TriplesConverted);
+    if duplicates != 0 || removed_triples != 0 {
+        observer.layer_progress(
+            id,
+            LayerProgress::Collapsed {
+                values: duplicates,
+                triples: removed_triples,
+            },
+        );
     }
     copy_unchanged_files(from_store, to_store, id).await?;
-    if verbose {
-        println!("files copied");
+    observer.layer_progress(id, LayerProgress::FilesCopied);
+    if !rebuild_triples {
+        rebuild_indexes(to_store, id, is_child)
+            .await
+            .map_err(|e| {
+                LayerConversionError::new(id, InnerLayerConversionError::RebuildIndexError(e))
+            })?;
     }
-    rebuild_indexes(to_store, id, is_child)
+    observer.layer_progress(id, LayerProgress::IndexesRebuilt);
+    PersistentLayerStore::finalize(to_store, id)
         .await
         .map_err(|e| {
-            LayerConversionError::new(id, InnerLayerConversionError::RebuildIndexError(e))
+            LayerConversionError::new(id, InnerLayerConversionError::FinalizationError(e))
         })?;
-    if verbose {
-        println!("indexes rebuilt");
+
+    write_parent_map(work, id, &mapping)
+        .await
+        .map_err(|e| {
+            LayerConversionError::new(id, InnerLayerConversionError::ParentMapWriteError(e))
+        })?;
+    observer.layer_progress(id, LayerProgress::ParentMapWritten);
+
+    Ok(fallbacks)
+}
+
+/// Copy a layer into the target store without converting it. This is
+/// for layers written after the upgrade, which contain no escaped
+/// strings. If its ancestors were renumbered, the ids in its triples
+/// and id map are still remapped. `rollup_of` is as for
+/// [`convert_layer_with_stores`].
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_layer_with_stores<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
+    work: &str,
+    memory_limit: usize,
+    observer: &dyn ConversionObserver,
+    id: [u32; 5],
+    rollup_of: Option<[u32; 5]>,
+) -> Result<(), LayerConversionError> {
+    let mut mapping = get_mapping(work, from_store, id)
+        .await
+        .map_err(|e| LayerConversionError::new(id, e))?;
+    let node_count = node_dictionary_count(from_store, id)
+        .await
+        .map_err(|e| LayerConversionError::new(id, e))?;
+    let value_count = value_dictionary_count(from_store, id)
+        .await
+        .map_err(|e| LayerConversionError::new(id, e))?;
+
+    PersistentLayerStore::create_named_directory(to_store, id)
+        .await
+        .map_err(|e| LayerConversionError::new(id, e))?;
+    if !mapping.is_identity() {
+        // the layer refers to ids of its ancestors, which were
+        // renumbered, so only its dictionaries can be copied as they
+        // are.
+        observer.layer_progress(id, LayerProgress::Remapping);
+        for filename in VALUE_DICTIONARY_FILES {
+            copy_file(from_store, to_store, id, filename).await?;
+        }
+        let is_child = PersistentLayerStore::layer_has_parent(from_store, id)
+            .await
+            .map_err(|e| LayerConversionError::new(id, e))?;
+        let values = ValueDictConversion {
+            old_num_entries: value_count,
+            new_num_entries: value_count,
+            reordering: None,
+            duplicates: 0,
+            fallbacks: Vec::new(),
+        };
+        remap_layer(
+            from_store,
+            to_store,
+            work,
+            memory_limit,
+            observer,
+            id,
+            rollup_of,
+            is_child,
+            mapping,
+            node_count,
+            values,
+        )
+        .await?;
+
+        return Ok(());
     }
-    map = Some(mapping);
-    offset = Some(offset_1);
+    mapping.push_layer(node_count, value_count, value_count, None);
 
+    // the rollup is linked separately, once it has been converted
+    for filename in FILENAME_ENUM_MAP.keys().filter(|name| **name != FILENAMES.rollup) {
+        copy_file(from_store, to_store, id, filename).await?;
+    }
     PersistentLayerStore::finalize(to_store, id)
         .await
         .map_err(|e| {
             LayerConversionError::new(id, InnerLayerConversionError::FinalizationError(e))
         })?;
+    observer.layer_progress(id, LayerProgress::LayerFilesCopied);
 
-    /*
-    // we copy the rollup only after finalizing, as rollups are not
-    // part of a layer under construction
-    copy_rollup_file(v10_store, v11_store, id)
+    write_parent_map(work, id, &mapping)
         .await
         .map_err(|e| {
-            LayerConversionError::new(id, InnerLayerConversionError::RollupFileCopyError(e))
+            LayerConversionError::new(id, InnerLayerConversionError::ParentMapWriteError(e))
         })?;
-    */
+    observer.layer_progress(id, LayerProgress::ParentMapWritten);
 
-    write_parent_map(&work, id, map.unwrap(), offset.unwrap())
+    Ok(())
+}
+
+/// Link a converted layer to its converted rollup, if it has one.
+/// This has to happen after both have been converted, as rollups are
+/// not part of a layer under construction. `is_converted` tells
+/// whether a layer was converted successfully, as a layer that failed
+/// may still be under construction.
+pub async fn copy_rollup_file<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
+    id: [u32; 5],
+    is_converted: impl Fn([u32; 5]) -> bool,
+) -> Result<(), LayerConversionError> {
+    inner_copy_rollup_file(from_store, to_store, id, is_converted)
         .await
         .map_err(|e| {
-            LayerConversionError::new(id, InnerLayerConversionError::ParentMapWriteError(e))
-        })?;
-    if verbose {
-        println!("written parent map to workdir");
+            LayerConversionError::new(id, InnerLayerConversionError::RollupFileCopyError(e))
+        })
+}
+
+async fn inner_copy_rollup_file<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
+    id: [u32; 5],
+    is_converted: impl Fn([u32; 5]) -> bool,
+) -> io::Result<()> {
+    if !PersistentLayerStore::layer_has_rollup(from_store, id).await? {
+        return Ok(());
+    }
+    let rollup = PersistentLayerStore::read_rollup_file(from_store, id).await?;
+    if is_converted(id) && is_converted(rollup) {
+        PersistentLayerStore::write_rollup_file(to_store, id, rollup).await?;
     }
 
     Ok(())
@@ -197,8 +530,6 @@ pub enum InnerParentMapError {
     ParentMapNotFound,
     #[error(transparent)]
     Io(#[from] io::Error),
-    #[error(transparent)]
-    Deserialization(#[from] postcard::Error),
 }
 
 #[derive(Error, Debug)]
@@ -221,374 +552,430 @@ impl ParentMapError {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct ParentMap {
-    offset: u64,
-    mapping: Vec<(u64, u64)>,
-}
-
-fn path_for_parent_map(workdir: &str, parent: [u32; 5]) -> PathBuf {
+pub(crate) fn path_for_parent_map(workdir: &str, parent: [u32; 5]) -> PathBuf {
     let parent_string = name_to_string(parent);
     let prefix = &parent_string[..3];
     let mut pathbuf = PathBuf::from(workdir);
     pathbuf.push(prefix);
-    pathbuf.push(format!("{parent_string}.postcard"));
+    pathbuf.push(format!("{parent_string}.idmap"));
 
     pathbuf
 }
 
-async fn get_mapping_and_offset_from_parent(
+async fn get_mapping_from_parent(
     workdir: &str,
     parent: [u32; 5],
-) -> Result<(HashMap<u64, u64>, u64), ParentMapError> {
+) -> Result<IdMapping, ParentMapError> {
     let pathbuf = path_for_parent_map(workdir, parent);
-    let file = tokio::fs::File::open(pathbuf).await;
-    if file.is_err() && file.as_ref().unwrap_err().kind() == io::ErrorKind::NotFound {
-        return Err(ParentMapError::new(
+    match IdMapping::load(&pathbuf).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(ParentMapError::new(
             parent,
             InnerParentMapError::ParentMapNotFound,
-        ));
+        )),
+        result => result.map_err(|e| ParentMapError::new(parent, e)),
     }
-    let mut file = file.map_err(|e| ParentMapError::new(parent, e))?;
-    let mut bytes = Vec::new();
-    file.read_to_end(&mut bytes)
-        .await
-        .map_err(|e| ParentMapError::new(parent, e))?;
-    let ParentMap {
-        offset,
-        mapping: mapping_vec,
-    } = postcard::from_bytes(&bytes).map_err(|e| ParentMapError::new(parent, e))?;
-    let mut mapping = HashMap::with_capacity(mapping_vec.len());
-    mapping.extend(mapping_vec);
-
-    Ok((mapping, offset))
 }
 
-async fn get_mapping_and_offset(
+async fn get_mapping<S: PersistentLayerStore>(
     workdir: &str,
-    store: &ArchiveLayerStore,
+    store: &S,
     id: [u32; 5],
-) -> Result<(HashMap<u64, u64>, u64), ParentMapError> {
+) -> Result<IdMapping, ParentMapError> {
     // look up parent id if applicable
     if let Some(parent) = LayerStore::get_layer_parent_name(store, id)
         .await
-        .map_err(|e| ParentMapError::Io(e))?
+        .map_err(ParentMapError::Io)?
     {
-        get_mapping_and_offset_from_parent(workdir, parent).await
+        get_mapping_from_parent(workdir, parent).await
     } else {
-        Ok((HashMap::with_capacity(0), 0))
+        Ok(IdMapping::default())
     }
 }
 
-async fn convert_triples(
-    from_store: &ArchiveLayerStore,
-    to_store: &ArchiveLayerStore,
+async fn node_dictionary_count<S: PersistentLayerStore>(
+    store: &S,
+    id: [u32; 5],
+) -> io::Result<u64> {
+    let offsets_file =
+        PersistentLayerStore::get_file(store, id, FILENAMES.node_dictionary_offsets).await?;
+    let blocks_file =
+        PersistentLayerStore::get_file(store, id, FILENAMES.node_dictionary_blocks).await?;
+    let dict = StringDict::parse(offsets_file.map().await?, blocks_file.map().await?);
+
+    Ok(dict.num_entries() as u64)
+}
+
+async fn value_dictionary_count<S: PersistentLayerStore>(
+    store: &S,
+    id: [u32; 5],
+) -> io::Result<u64> {
+    let types_present_file =
+        PersistentLayerStore::get_file(store, id, FILENAMES.value_dictionary_types_present).await?;
+    let type_offsets_file =
+        PersistentLayerStore::get_file(store, id, FILENAMES.value_dictionary_type_offsets).await?;
+    let offsets_file =
+        PersistentLayerStore::get_file(store, id, FILENAMES.value_dictionary_offsets).await?;
+    let blocks_file =
+        PersistentLayerStore::get_file(store, id, FILENAMES.value_dictionary_blocks).await?;
+    let dict = TypedDict::from_parts(
+        types_present_file.map().await?,
+        type_offsets_file.map().await?,
+        offsets_file.map().await?,
+        blocks_file.map().await?,
+    );
+
+    Ok(dict.num_entries() as u64)
+}
+
+async fn convert_triples<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
     id: [u32; 5],
     is_child: bool,
-    mapping: &HashMap<u64, u64>,
-) -> io::Result<()> {
-    if is_child {
-        let pos_bits = PersistentLayerStore::get_file(
+    mapping: &IdMapping,
+) -> io::Result<u64> {
+    let removed = if is_child {
+        convert_sp_o(
             from_store,
+            to_store,
             id,
+            FILENAMES.pos_sp_o_adjacency_list_nums,
             FILENAMES.pos_sp_o_adjacency_list_bits,
+            FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks,
+            FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks,
+            mapping,
         )
-        .await?;
-        let pos_nums = PersistentLayerStore::get_file(
+        .await?
+            + convert_sp_o(
+                from_store,
+                to_store,
+                id,
+                FILENAMES.neg_sp_o_adjacency_list_nums,
+                FILENAMES.neg_sp_o_adjacency_list_bits,
+                FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
+                FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
+                mapping,
+            )
+            .await?
+    } else {
+        convert_sp_o(
             from_store,
+            to_store,
             id,
-            FILENAMES.pos_sp_o_adjacency_list_nums,
+            FILENAMES.base_sp_o_adjacency_list_nums,
+            FILENAMES.base_sp_o_adjacency_list_bits,
+            FILENAMES.base_sp_o_adjacency_list_bit_index_blocks,
+            FILENAMES.base_sp_o_adjacency_list_bit_index_sblocks,
+            mapping,
         )
-        .await?;
+        .await?
+    };
 
-        let output_nums = convert_sp_o_nums(pos_bits, pos_nums, mapping).await?;
-        write_bytes_to_file(
-            to_store,
-            id,
-            FILENAMES.pos_sp_o_adjacency_list_nums,
-            output_nums,
-        );
+    if !mapping.is_identity() {
+        // subjects may refer to nodes in ancestor layers, whose ids
+        // shift if an ancestor collapsed values. In a rollup they also
+        // refer to the nodes of every layer it rolls up.
+        let subject_files: &[&str] = if is_child {
+            &[FILENAMES.pos_subjects, FILENAMES.neg_subjects]
+        } else {
+            &[FILENAMES.base_subjects]
+        };
+        for filename in subject_files {
+            if !PersistentLayerStore::file_exists(from_store, id, filename).await? {
+                continue;
+            }
+            let subjects = PersistentLayerStore::get_file(from_store, id, filename).await?;
+            let output_subjects = PersistentLayerStore::get_file(to_store, id, filename).await?;
+            convert_subjects(subjects, mapping, output_subjects.open_write().await?).await?;
+        }
+    }
 
-        let neg_bits = PersistentLayerStore::get_file(
-            from_store,
-            id,
-            FILENAMES.neg_sp_o_adjacency_list_bits,
-        )
-        .await?;
-        let neg_nums = PersistentLayerStore::get_file(
-            from_store,
-            id,
-            FILENAMES.neg_sp_o_adjacency_list_nums,
-        )
-        .await?;
+    Ok(removed)
+}
+
+/// Rebuild the triples and indexes of a base layer from its mapped
+/// triples, which are sorted again, spilling sorted runs to the workdir
+/// when they take more than `memory_limit` bytes.
+#[allow(clippy::too_many_arguments)]
+async fn rebuild_base_triples<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
+    work: &str,
+    memory_limit: usize,
+    id: [u32; 5],
+    node_count: u64,
+    value_count: u64,
+    mapping: &IdMapping,
+) -> io::Result<u64> {
+    let layer = LayerStore::get_layer(from_store, id)
+        .await?
+        .expect("layer to convert should exist");
+    let scratch = ScratchDir::for_layer(work, id);
+    let mut sorter = ExternalSorter::new(&scratch, memory_limit);
+    sorter.reserve(mapping.memory());
+    for t in layer.internal_triple_additions() {
+        sorter.push(IdTriple::new(mapping.get(t.subject), t.predicate, mapping.get(t.object)))?;
+    }
 
-        let output_nums = convert_sp_o_nums(neg_bits, neg_nums, mapping).await?;
-        write_bytes_to_file(
+    // only fetch the files that are rebuilt here, as fetching a file
+    // from a layer under construction makes it exist.
+    let s_p_files = adjacency_list_files(
+        to_store,
+        id,
+        FILENAMES.base_s_p_adjacency_list_nums,
+        FILENAMES.base_s_p_adjacency_list_bits,
+        FILENAMES.base_s_p_adjacency_list_bit_index_blocks,
+        FILENAMES.base_s_p_adjacency_list_bit_index_sblocks,
+    )
+    .await?;
+    let sp_o_files = adjacency_list_files(
+        to_store,
+        id,
+        FILENAMES.base_sp_o_adjacency_list_nums,
+        FILENAMES.base_sp_o_adjacency_list_bits,
+        FILENAMES.base_sp_o_adjacency_list_bit_index_blocks,
+        FILENAMES.base_sp_o_adjacency_list_bit_index_sblocks,
+    )
+    .await?;
+    let o_ps_files = adjacency_list_files(
+        to_store,
+        id,
+        FILENAMES.base_o_ps_adjacency_list_nums,
+        FILENAMES.base_o_ps_adjacency_list_bits,
+        FILENAMES.base_o_ps_adjacency_list_bit_index_blocks,
+        FILENAMES.base_o_ps_adjacency_list_bit_index_sblocks,
+    )
+    .await?;
+    let wavelet_files = BitIndexFiles {
+        bits_file: PersistentLayerStore::get_file(
             to_store,
             id,
-            FILENAMES.neg_sp_o_adjacency_list_nums,
-            output_nums,
-        );
-    } else {
-        let base_bits = PersistentLayerStore::get_file(
-            from_store,
-            id,
-            FILENAMES.base_sp_o_adjacency_list_bits,
+            FILENAMES.base_predicate_wavelet_tree_bits,
         )
-        .await?;
-        let base_nums = PersistentLayerStore::get_file(
-            from_store,
+        .await?,
+        blocks_file: PersistentLayerStore::get_file(
+            to_store,
             id,
-            FILENAMES.base_sp_o_adjacency_list_nums,
+            FILENAMES.base_predicate_wavelet_tree_bit_index_blocks,
         )
-        .await?;
-
-        let output_nums = convert_sp_o_nums(base_bits, base_nums, mapping).await?;
-        write_bytes_to_file(
+        .await?,
+        sblocks_file: PersistentLayerStore::get_file(
             to_store,
             id,
-            FILENAMES.base_sp_o_adjacency_list_nums,
-            output_nums,
-        );
+            FILENAMES.base_predicate_wavelet_tree_bit_index_sblocks,
+        )
+        .await?,
+    };
+
+    let mut builder = TripleFileBuilder::new(
+        s_p_files.clone(),
+        sp_o_files.clone(),
+        node_count as usize,
+        layer.predicate_dict_len(),
+        value_count as usize,
+        None,
+    )
+    .await?;
+    let mut removed = 0;
+    let mut last = None;
+    for triple in sorter.finish()? {
+        let triple = triple?;
+        if last == Some(triple) {
+            removed += 1;
+            continue;
+        }
+        builder.add_triple(triple.subject, triple.predicate, triple.object).await?;
+        last = Some(triple);
     }
+    builder.finalize().await?;
 
-    Ok(())
+    build_indexes(s_p_files, sp_o_files, o_ps_files, None, wavelet_files).await?;
+
+    Ok(removed)
 }
 
-async fn copy_unchanged_files(
-    from: &ArchiveLayerStore,
-    to: &ArchiveLayerStore,
+async fn adjacency_list_files<S: PersistentLayerStore>(
+    store: &S,
     id: [u32; 5],
-) -> Result<(), LayerConversionError> {
-    for filename in UNCHANGED_FILES.iter() {
-        copy_file(from, to, id, filename).await?;
-    }
+    nums_name: &str,
+    bits_name: &str,
+    blocks_name: &str,
+    sblocks_name: &str,
+) -> io::Result<AdjacencyListFiles<S::File>> {
+    Ok(AdjacencyListFiles {
+        bitindex_files: BitIndexFiles {
+            bits_file: PersistentLayerStore::get_file(store, id, bits_name).await?,
+            blocks_file: PersistentLayerStore::get_file(store, id, blocks_name).await?,
+            sblocks_file: PersistentLayerStore::get_file(store, id, sblocks_name).await?,
+        },
+        nums_file: PersistentLayerStore::get_file(store, id, nums_name).await?,
+    })
+}
 
-    Ok(())
+#[allow(clippy::too_many_arguments)]
+async fn convert_sp_o<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
+    id: [u32; 5],
+    nums_name: &str,
+    bits_name: &str,
+    blocks_name: &str,
+    sblocks_name: &str,
+    mapping: &IdMapping,
+) -> io::Result<u64> {
+    let bits = PersistentLayerStore::get_file(from_store, id, bits_name).await?;
+    let nums = PersistentLayerStore::get_file(from_store, id, nums_name).await?;
+    let output_bits = PersistentLayerStore::get_file(to_store, id, bits_name).await?;
+    let output_nums = PersistentLayerStore::get_file(to_store, id, nums_name).await?;
+    let removed = convert_sp_o_nums(
+        bits,
+        nums,
+        mapping,
+        output_bits.open_write().await?,
+        output_nums.open_write().await?,
+    )
+    .await?;
+
+    let output_blocks = PersistentLayerStore::get_file(to_store, id, blocks_name).await?;
+    let output_sblocks = PersistentLayerStore::get_file(to_store, id, sblocks_name).await?;
+    build_bitindex(
+        output_bits.open_read().await?,
+        output_blocks.open_write().await?,
+        output_sblocks.open_write().await?,
+    )
+    .await?;
+
+    Ok(removed)
 }
 
-async fn copy_indexes(
-    from: &ArchiveLayerStore,
-    to: &ArchiveLayerStore,
+async fn copy_unchanged_files<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from: &F,
+    to: &T,
     id: [u32; 5],
-    is_child: bool,
 ) -> Result<(), LayerConversionError> {
-    let iter = if is_child {
-        CHILD_INDEX_FILES.iter()
-    } else {
-        BASE_INDEX_FILES.iter()
-    };
-    for filename in iter {
+    for filename in UNCHANGED_FILES.iter() {
         copy_file(from, to, id, filename).await?;
     }
 
     Ok(())
 }
 
-async fn rebuild_indexes(
-    store: &ArchiveLayerStore,
+async fn rebuild_indexes<S: PersistentLayerStore>(
+    store: &S,
     id: [u32; 5],
     is_child: bool,
 ) -> io::Result<()> {
-    let pos_objects_file = if is_child {
-        Some(
-            PersistentLayerStore::get_file(store, id, FILENAMES.pos_objects)
-                .await?,
+    if !is_child {
+        let sp_o_files = adjacency_list_files(
+            store,
+            id,
+            FILENAMES.base_sp_o_adjacency_list_nums,
+            FILENAMES.base_sp_o_adjacency_list_bits,
+            FILENAMES.base_sp_o_adjacency_list_bit_index_blocks,
+            FILENAMES.base_sp_o_adjacency_list_bit_index_sblocks,
         )
-    } else {
-        None
-    };
+        .await?;
+        let o_ps_files = adjacency_list_files(
+            store,
+            id,
+            FILENAMES.base_o_ps_adjacency_list_nums,
+            FILENAMES.base_o_ps_adjacency_list_bits,
+            FILENAMES.base_o_ps_adjacency_list_bit_index_blocks,
+            FILENAMES.base_o_ps_adjacency_list_bit_index_sblocks,
+        )
+        .await?;
+
+        return build_object_index(sp_o_files, o_ps_files, None).await;
+    }
 
-    let pos_sp_o_nums = PersistentLayerStore::get_file(
+    let pos_sp_o_files = adjacency_list_files(
         store,
         id,
         FILENAMES.pos_sp_o_adjacency_list_nums,
-    )
-    .await?;
-    let pos_sp_o_bits = PersistentLayerStore::get_file(
-        store,
-        id,
         FILENAMES.pos_sp_o_adjacency_list_bits,
-    )
-    .await?;
-    let pos_sp_o_bit_index_blocks = PersistentLayerStore::get_file(
-        store,
-        id,
         FILENAMES.pos_sp_o_adjacency_list_bit_index_blocks,
-    )
-    .await?;
-    let pos_sp_o_bit_index_sblocks = PersistentLayerStore::get_file(
-        store,
-        id,
         FILENAMES.pos_sp_o_adjacency_list_bit_index_sblocks,
     )
     .await?;
-
-    let pos_sp_o_files = AdjacencyListFiles {
-        bitindex_files: BitIndexFiles {
-            bits_file: pos_sp_o_bits,
-            blocks_file: pos_sp_o_bit_index_blocks,
-            sblocks_file: pos_sp_o_bit_index_sblocks,
-        },
-        nums_file: pos_sp_o_nums,
-    };
-
-    let pos_o_ps_nums = PersistentLayerStore::get_file(
+    let pos_o_ps_files = adjacency_list_files(
         store,
         id,
         FILENAMES.pos_o_ps_adjacency_list_nums,
-    )
-    .await?;
-    let pos_o_ps_bits = PersistentLayerStore::get_file(
-        store,
-        id,
         FILENAMES.pos_o_ps_adjacency_list_bits,
+        FILENAMES.pos_o_ps_adjacency_list_bit_index_blocks,
+        FILENAMES.pos_o_ps_adjacency_list_bit_index_sblocks,
     )
     .await?;
-    let pos_o_ps_bit_index_blocks = PersistentLayerStore::get_file(
+    let pos_objects_file = PersistentLayerStore::get_file(store, id, FILENAMES.pos_objects).await?;
+    build_object_index(pos_sp_o_files, pos_o_ps_files, Some(pos_objects_file)).await?;
+
+    let neg_sp_o_files = adjacency_list_files(
         store,
         id,
-        FILENAMES.pos_o_ps_adjacency_list_bit_index_blocks,
+        FILENAMES.neg_sp_o_adjacency_list_nums,
+        FILENAMES.neg_sp_o_adjacency_list_bits,
+        FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
+        FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
     )
     .await?;
-    let pos_o_ps_bit_index_sblocks = PersistentLayerStore::get_file(
+    let neg_o_ps_files = adjacency_list_files(
         store,
         id,
-        FILENAMES.pos_o_ps_adjacency_list_bit_index_sblocks,
+        FILENAMES.neg_o_ps_adjacency_list_nums,
+        FILENAMES.neg_o_ps_adjacency_list_bits,
+        FILENAMES.neg_o_ps_adjacency_list_bit_index_blocks,
+        FILENAMES.neg_o_ps_adjacency_list_bit_index_sblocks,
     )
     .await?;
-
-    let pos_o_ps_files = AdjacencyListFiles {
-        bitindex_files: BitIndexFiles {
-            bits_file: pos_o_ps_bits,
-            blocks_file: pos_o_ps_bit_index_blocks,
-            sblocks_file: pos_o_ps_bit_index_sblocks,
-        },
-        nums_file: pos_o_ps_nums,
-    };
-
-    build_object_index(pos_sp_o_files, pos_o_ps_files, pos_objects_file).await?;
-
-    if is_child {
-        let neg_objects_file = Some(
-            PersistentLayerStore::get_file(store, id, FILENAMES.neg_objects)
-                .await?,
-        );
-
-        let neg_sp_o_nums = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_sp_o_adjacency_list_nums,
-        )
-        .await?;
-        let neg_sp_o_bits = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_sp_o_adjacency_list_bits,
-        )
-        .await?;
-        let neg_sp_o_bit_index_blocks = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_sp_o_adjacency_list_bit_index_blocks,
-        )
-        .await?;
-        let neg_sp_o_bit_index_sblocks = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_sp_o_adjacency_list_bit_index_sblocks,
-        )
-        .await?;
-
-        let neg_sp_o_files = AdjacencyListFiles {
-            bitindex_files: BitIndexFiles {
-                bits_file: neg_sp_o_bits,
-                blocks_file: neg_sp_o_bit_index_blocks,
-                sblocks_file: neg_sp_o_bit_index_sblocks,
-            },
-            nums_file: neg_sp_o_nums,
-        };
-
-        let neg_o_ps_nums = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_o_ps_adjacency_list_nums,
-        )
-        .await?;
-        let neg_o_ps_bits = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_o_ps_adjacency_list_bits,
-        )
-        .await?;
-        let neg_o_ps_bit_index_blocks = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_o_ps_adjacency_list_bit_index_blocks,
-        )
-        .await?;
-        let neg_o_ps_bit_index_sblocks = PersistentLayerStore::get_file(
-            store,
-            id,
-            FILENAMES.neg_o_ps_adjacency_list_bit_index_sblocks,
-        )
-        .await?;
-
-        let neg_o_ps_files = AdjacencyListFiles {
-            bitindex_files: BitIndexFiles {
-                bits_file: neg_o_ps_bits,
-                blocks_file: neg_o_ps_bit_index_blocks,
-                sblocks_file: neg_o_ps_bit_index_sblocks,
-            },
-            nums_file: neg_o_ps_nums,
-        };
-
-        build_object_index(neg_sp_o_files, neg_o_ps_files, neg_objects_file).await?;
-    }
-
-    Ok(())
+    let neg_objects_file = PersistentLayerStore::get_file(store, id, FILENAMES.neg_objects).await?;
+    build_object_index(neg_sp_o_files, neg_o_ps_files, Some(neg_objects_file)).await
 }
 
-async fn write_parent_map(
-    workdir: &str,
-    id: [u32; 5],
-    mapping: HashMap<u64, u64>,
-    offset: u64,
-) -> io::Result<()> {
+async fn write_parent_map(workdir: &str, id: [u32; 5], mapping: &IdMapping) -> io::Result<()> {
     let pathbuf = path_for_parent_map(workdir, id);
     tokio::fs::create_dir_all(pathbuf.parent().unwrap()).await?;
 
+    // written to a temporary file first, so that an interrupted write
+    // never leaves a half-written parent map behind.
+    let tmp_pathbuf = pathbuf.with_extension("idmap.tmp");
     let mut options = tokio::fs::OpenOptions::new();
-    options.create(true).write(true);
-
-    let mut file = options.open(pathbuf).await?;
+    options.create(true).write(true).truncate(true);
 
-    let mut map_vec: Vec<_> = mapping.into_iter().collect();
-    map_vec.sort();
+    let mut file = options.open(&tmp_pathbuf).await?;
 
-    let parent_map = ParentMap {
-        mapping: map_vec,
-        offset,
-    };
+    file.write_all(&mapping.to_bytes()).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    tokio::fs::rename(tmp_pathbuf, pathbuf).await
+}
 
-    let v = postcard::to_allocvec(&parent_map).unwrap();
-    file.write_all(&v).await?;
-    file.flush().await
+/// Remove the parent map of a layer from the workdir. A parent map
+/// that does not exist is not an error.
+pub(crate) async fn remove_parent_map(workdir: &str, id: [u32; 5]) -> io::Result<()> {
+    match tokio::fs::remove_file(path_for_parent_map(workdir, id)).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
 }
 
-fn write_bytes_to_file(
-    store: &ArchiveLayerStore,
+pub(crate) async fn write_bytes_to_file<S: PersistentLayerStore>(
+    store: &S,
     id: [u32; 5],
     file: &str,
     bytes: Bytes,
-) {
-    let file = FILENAME_ENUM_MAP[file];
-    store.write_bytes(id, file, bytes);
+) -> io::Result<()> {
+    let mut writer = PersistentLayerStore::get_file(store, id, file)
+        .await?
+        .open_write()
+        .await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    writer.sync_all().await
 }
 
-async fn copy_file(
-    from: &ArchiveLayerStore,
-    to: &ArchiveLayerStore,
+async fn copy_file<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from: &F,
+    to: &T,
     id: [u32; 5],
     file: &str,
 ) -> Result<(), LayerConversionError> {
@@ -602,9 +989,9 @@ async fn copy_file(
         )
     })
 }
-async fn inner_copy_file(
-    from: &ArchiveLayerStore,
-    to: &ArchiveLayerStore,
+async fn inner_copy_file<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from: &F,
+    to: &T,
     id: [u32; 5],
     file: &str,
 ) -> io::Result<()> {
@@ -612,27 +999,566 @@ async fn inner_copy_file(
     // which should be correct for everythning that is not a
     // dictionary. At this point, we've already copied over the
     // dictionaries.
+    if PersistentLayerStore::file_exists(to, id, file).await? {
+        // the file was rewritten as part of the conversion
+        return Ok(());
+    }
     let input = PersistentLayerStore::get_file(from, id, file).await?;
     if let Some(map) = FileLoad::map_if_exists(&input).await? {
-        write_bytes_to_file(to, id, file, map);
+        write_bytes_to_file(to, id, file, map).await?;
     }
 
     Ok(())
 }
 
-async fn assert_no_remap_exists(
-    store: &ArchiveLayerStore,
+/// Write the node/value id map of a converted layer, returning the
+/// mapping for the ids of the layer. The new inner ids follow from the
+/// dictionary conversion. The new outer ids of a rollup are those of
+/// the layer it rolls up, while any other layer keeps its outer ids in
+/// their old order.
+#[allow(clippy::too_many_arguments)]
+async fn convert_node_value_idmap<S: PersistentLayerStore>(
+    to_store: &S,
+    work: &str,
     id: [u32; 5],
-) -> Result<(), InnerLayerConversionError> {
-    if PersistentLayerStore::file_exists(
-        store,
-        id,
-        FILENAMES.node_value_idmap_bit_index_blocks,
+    rollup_of: Option<[u32; 5]>,
+    mut mapping: IdMapping,
+    idmap: &impl OuterIds,
+    node_count: u64,
+    new_value_count: u64,
+    value_reordering: Option<&[u64]>,
+) -> Result<IdMapping, InnerLayerConversionError> {
+    let new_inner = |inner: u64| match value_reordering {
+        Some(reordering) if inner > node_count => {
+            node_count + reordering[(inner - node_count - 1) as usize] + 1
+        }
+        _ => inner,
+    };
+    let new_count = node_count + new_value_count;
+    let (layer_mapping, outer_remap, expected) = match rollup_of {
+        Some(rolled_up) => {
+            let rolled_up_mapping = get_mapping_from_parent(work, rolled_up).await?;
+            if rolled_up_mapping.old_count() != mapping.old_count() + idmap.count() {
+                return Err(InnerLayerConversionError::IdMapMismatch(rolled_up));
+            }
+            let outer_remap: Vec<u64> = (1..=idmap.count())
+                .map(|outer| {
+                    rolled_up_mapping.get(mapping.old_count() + outer) - mapping.new_count()
+                })
+                .collect();
+            (rolled_up_mapping, outer_remap, rolled_up)
+        }
+        None => {
+            let outer_remap = compact_outer_ids(idmap, new_inner, new_count);
+            mapping.push_remapped_layer(new_count, &outer_remap);
+            (mapping, outer_remap, id)
+        }
+    };
+    let new_idmap = remap_idmap(idmap, &outer_remap, new_inner, new_count)
+        .ok_or(InnerLayerConversionError::IdMapMismatch(expected))?;
+    write_node_value_idmap(to_store, id, &new_idmap)
+        .await
+        .map_err(InnerLayerConversionError::IdMapWriteError)?;
+
+    Ok(layer_mapping)
+}
+
+/// The outer id of every inner id of a layer, as in its node/value id
+/// map. Ids count from 1.
+trait OuterIds {
+    /// The number of inner ids.
+    fn count(&self) -> u64;
+    fn outer(&self, inner: u64) -> u64;
+}
+
+impl OuterIds for [u64] {
+    fn count(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn outer(&self, inner: u64) -> u64 {
+        self[inner as usize - 1]
+    }
+}
+
+/// The node/value id map of a layer. Outer ids are looked up in the
+/// id map files of the layer rather than collected.
+struct LayerIdMap {
+    idmap: IdMap,
+    count: u64,
+}
+
+impl OuterIds for LayerIdMap {
+    fn count(&self) -> u64 {
+        self.count
+    }
+
+    fn outer(&self, inner: u64) -> u64 {
+        self.idmap.inner_to_outer(inner)
+    }
+}
+
+/// Read the node/value id map of a layer with `count` nodes and
+/// values, or `None` if the layer does not have one.
+async fn read_node_value_idmap<S: PersistentLayerStore>(
+    store: &S,
+    id: [u32; 5],
+    count: u64,
+) -> io::Result<Option<LayerIdMap>> {
+    if !PersistentLayerStore::file_exists(store, id, FILENAMES.node_value_idmap_bits).await? {
+        return Ok(None);
+    }
+    let idmap = LayerStore::get_node_value_idmap(store, id)
+        .await?
+        .expect("layer with an id map should exist");
+
+    Ok(Some(LayerIdMap { idmap, count }))
+}
+
+/// Number the outer ids of a layer after its entries moved or
+/// collapsed. The ids keep their old order, with collapsed entries
+/// taking the lowest of their old ids. Returns the new outer id of
+/// every old outer id.
+fn compact_outer_ids<I: OuterIds + ?Sized, F: Fn(u64) -> u64>(
+    idmap: &I,
+    new_inner: F,
+    new_count: u64,
+) -> Vec<u64> {
+    let mut lowest_outer = vec![u64::MAX; new_count as usize];
+    for inner in 1..=idmap.count() {
+        let slot = &mut lowest_outer[new_inner(inner) as usize - 1];
+        *slot = (*slot).min(idmap.outer(inner));
+    }
+    let mut order: Vec<usize> = (0..new_count as usize).collect();
+    order.sort_by_key(|ix| lowest_outer[*ix]);
+    let mut new_outer = vec![0; new_count as usize];
+    for (rank, ix) in order.into_iter().enumerate() {
+        new_outer[ix] = rank as u64 + 1;
+    }
+
+    let mut outer_remap = vec![0; idmap.count() as usize];
+    for inner in 1..=idmap.count() {
+        outer_remap[idmap.outer(inner) as usize - 1] = new_outer[new_inner(inner) as usize - 1];
+    }
+
+    outer_remap
+}
+
+/// Compose an old id map with the new inner ids from the dictionary
+/// conversion and the new outer ids. Returns `None` if the result is
+/// not a permutation, which happens when entries collapsed into one
+/// inner id while their outer ids did not.
+fn remap_idmap<I: OuterIds + ?Sized, F: Fn(u64) -> u64>(
+    idmap: &I,
+    outer_remap: &[u64],
+    new_inner: F,
+    new_count: u64,
+) -> Option<Vec<u64>> {
+    let mut new_idmap = vec![0; new_count as usize];
+    for inner in 1..=idmap.count() {
+        let new_outer = *outer_remap.get((idmap.outer(inner) as usize).checked_sub(1)?)?;
+        let slot = &mut new_idmap[new_inner(inner) as usize - 1];
+        if *slot != 0 && *slot != new_outer {
+            return None;
+        }
+        *slot = new_outer;
+    }
+
+    let mut seen = vec![false; new_count as usize];
+    for outer in new_idmap.iter() {
+        let seen = seen.get_mut((*outer as usize).checked_sub(1)?)?;
+        if *seen {
+            return None;
+        }
+        *seen = true;
+    }
+
+    Some(new_idmap)
+}
+
+async fn write_node_value_idmap<S: PersistentLayerStore>(
+    store: &S,
+    id: [u32; 5],
+    idmap: &[u64],
+) -> io::Result<()> {
+    let bits = PersistentLayerStore::get_file(store, id, FILENAMES.node_value_idmap_bits).await?;
+    let blocks =
+        PersistentLayerStore::get_file(store, id, FILENAMES.node_value_idmap_bit_index_blocks)
+            .await?;
+    let sblocks =
+        PersistentLayerStore::get_file(store, id, FILENAMES.node_value_idmap_bit_index_sblocks)
+            .await?;
+
+    build_wavelet_tree_from_iter(
+        calculate_width(idmap.len() as u64),
+        idmap.iter().map(|outer| outer - 1),
+        bits,
+        blocks,
+        sblocks,
     )
-    .await?
-    {
-        Err(InnerLayerConversionError::NodeValueRemapExists)
-    } else {
-        Ok(())
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert_store::DEFAULT_MEMORY_LIMIT;
+    use crate::observer::PrintObserver;
+    use terminus_store::layer::{Layer, ValueTriple};
+    use terminus_store::storage::memory::MemoryLayerStore;
+    use terminus_store::structure::TdbDataType;
+
+    #[tokio::test]
+    async fn convert_layers_between_memory_stores() {
+        let work = std::env::temp_dir().join(format!("escape-fixup-test-{}", std::process::id()));
+        let work = work.to_str().unwrap();
+        let from_store = MemoryLayerStore::new();
+        let to_store = MemoryLayerStore::new();
+
+        let mut builder = from_store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "moo\\tmoo"));
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "quack"));
+        builder.commit_boxed().await.unwrap();
+
+        let mut builder = from_store.create_child_layer(base).await.unwrap();
+        let child = builder.name();
+        builder.remove_value_triple(ValueTriple::new_string_value("duck", "says", "quack"));
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "\\\"quack\\\""));
+        builder.commit_boxed().await.unwrap();
+
+        for id in [base, child] {
+            let fallbacks = convert_layer_with_stores(
+                &from_store,
+                &to_store,
+                work,
+                DEFAULT_MEMORY_LIMIT,
+                &PrintObserver::default(),
+                MalformedEscapePolicy::Strict,
+                false,
+                id,
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(fallbacks.is_empty());
+        }
+        std::fs::remove_dir_all(work).unwrap();
+
+        let converted = to_store.get_layer(child).await.unwrap().unwrap();
+        assert!(converted.value_triple_exists(&ValueTriple::new_string_value("cow", "says", "moo\tmoo")));
+        assert!(converted.value_triple_exists(&ValueTriple::new_string_value("duck", "says", "\"quack\"")));
+        assert!(!converted.value_triple_exists(&ValueTriple::new_string_value("duck", "says", "quack")));
+        assert_eq!(Some(base), converted.parent_name());
+    }
+
+    #[tokio::test]
+    async fn copied_layer_is_remapped_when_ancestors_are_renumbered() {
+        let work = std::env::temp_dir().join(format!("escape-fixup-copy-test-{}", std::process::id()));
+        let work = work.to_str().unwrap();
+        let from_store = MemoryLayerStore::new();
+        let to_store = MemoryLayerStore::new();
+
+        // `a\x41\` collapses into `aA`, so `zz` moves to a lower id
+        let mut builder = from_store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "a\\x41\\"));
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "aA"));
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "zz"));
+        builder.commit_boxed().await.unwrap();
+
+        // written after the upgrade, referring to `zz` in the base
+        let mut builder = from_store.create_child_layer(base).await.unwrap();
+        let child = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "zz"));
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "a\\tb"));
+        builder.remove_value_triple(ValueTriple::new_string_value("duck", "says", "zz"));
+        builder.commit_boxed().await.unwrap();
+
+        convert_layer_with_stores(
+            &from_store,
+            &to_store,
+            work,
+            DEFAULT_MEMORY_LIMIT,
+            &PrintObserver::default(),
+            MalformedEscapePolicy::Strict,
+            false,
+            base,
+            None,
+        )
+        .await
+        .unwrap();
+        copy_layer_with_stores(&from_store, &to_store, work, DEFAULT_MEMORY_LIMIT, &PrintObserver::default(), child, None)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(work).unwrap();
+
+        let copied = to_store.get_layer(child).await.unwrap().unwrap();
+        let mut triples: Vec<_> = copied
+            .triples()
+            .map(|t| copied.id_triple_to_string(&t).unwrap())
+            .collect();
+        triples.sort();
+        assert_eq!(
+            vec![
+                ValueTriple::new_string_value("cow", "says", "aA"),
+                ValueTriple::new_string_value("cow", "says", "a\\tb"),
+                ValueTriple::new_string_value("cow", "says", "zz"),
+            ],
+            triples
+        );
+    }
+
+    fn workdir(name: &str) -> String {
+        let work = std::env::temp_dir().join(format!("escape-fixup-{name}-{}", std::process::id()));
+        work.to_str().unwrap().to_string()
+    }
+
+    async fn convert(
+        from_store: &MemoryLayerStore,
+        to_store: &MemoryLayerStore,
+        work: &str,
+        id: [u32; 5],
+        rollup_of: Option<[u32; 5]>,
+    ) {
+        convert_within(from_store, to_store, work, DEFAULT_MEMORY_LIMIT, id, rollup_of).await
+    }
+
+    async fn convert_within(
+        from_store: &MemoryLayerStore,
+        to_store: &MemoryLayerStore,
+        work: &str,
+        memory_limit: usize,
+        id: [u32; 5],
+        rollup_of: Option<[u32; 5]>,
+    ) {
+        convert_layer_with_stores(
+            from_store,
+            to_store,
+            work,
+            memory_limit,
+            &PrintObserver::default(),
+            MalformedEscapePolicy::Strict,
+            false,
+            id,
+            rollup_of,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn string_triples(layer: &dyn Layer) -> Vec<ValueTriple> {
+        let mut triples: Vec<_> = layer
+            .triples()
+            .map(|t| layer.id_triple_to_string(&t).unwrap())
+            .collect();
+        triples.sort();
+        triples
+    }
+
+    /// A base layer where `a\x41\` collapses into `aA`, a child layer
+    /// whose values reorder and collapse, and a grandchild.
+    async fn layer_stack(store: &MemoryLayerStore) -> ([u32; 5], [u32; 5], [u32; 5]) {
+        let mut builder = store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "a\\x41\\"));
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "aA"));
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "zz"));
+        builder.commit_boxed().await.unwrap();
+
+        let mut builder = store.create_child_layer(base).await.unwrap();
+        let child = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("pig", "says", "a\\x42\\"));
+        builder.add_value_triple(ValueTriple::new_string_value("pig", "says", "aB"));
+        builder.add_value_triple(ValueTriple::new_string_value("pig", "says", "\\x40\\"));
+        // `z` sorts after `b` once decoded
+        builder.add_value_triple(ValueTriple::new_string_value("pig", "says", "\\x7a\\"));
+        builder.add_value_triple(ValueTriple::new_string_value("pig", "says", "b"));
+        builder.remove_value_triple(ValueTriple::new_string_value("duck", "says", "zz"));
+        builder.commit_boxed().await.unwrap();
+
+        let mut builder = store.create_child_layer(child).await.unwrap();
+        let grandchild = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "aB"));
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "b\\tb"));
+        builder.commit_boxed().await.unwrap();
+
+        (base, child, grandchild)
+    }
+
+    /// The rollup of a layer has to use the same ids as the layer.
+    fn assert_same_ids(layer: &dyn Layer, rollup: &dyn Layer) {
+        let triples = string_triples(layer);
+        assert_eq!(triples, string_triples(rollup));
+        for triple in triples.iter() {
+            assert_eq!(layer.value_triple_to_id(triple), rollup.value_triple_to_id(triple));
+        }
+        let object = layer.object_value_id(&String::make_entry(&"aB")).unwrap();
+        assert_eq!(2, rollup.triples_o(object).count());
+    }
+
+    #[tokio::test]
+    async fn rollup_uses_the_ids_of_the_converted_layer() {
+        let work = workdir("rollup-test");
+        let from_store = MemoryLayerStore::new();
+        let to_store = MemoryLayerStore::new();
+        let (base, child, grandchild) = layer_stack(&from_store).await;
+        let layer = from_store.get_layer(grandchild).await.unwrap().unwrap();
+        let rollup = std::sync::Arc::new(from_store.clone()).rollup(layer).await.unwrap();
+
+        for layer in [base, child, grandchild] {
+            convert(&from_store, &to_store, &work, layer, None).await;
+        }
+        convert(&from_store, &to_store, &work, rollup, Some(grandchild)).await;
+        std::fs::remove_dir_all(&work).unwrap();
+
+        let converted = to_store.get_layer(grandchild).await.unwrap().unwrap();
+        let converted_rollup = to_store.get_layer(rollup).await.unwrap().unwrap();
+        assert_eq!(None, converted_rollup.parent_name());
+        assert_same_ids(&*converted, &*converted_rollup);
+        assert!(converted.value_triple_exists(&ValueTriple::new_string_value("pig", "says", "@")));
+    }
+
+    #[tokio::test]
+    async fn rollup_sorted_in_spilled_runs_uses_the_ids_of_the_converted_layer() {
+        let work = workdir("rollup-spilled-test");
+        let from_store = MemoryLayerStore::new();
+        let to_store = MemoryLayerStore::new();
+        let (base, child, grandchild) = layer_stack(&from_store).await;
+        let layer = from_store.get_layer(grandchild).await.unwrap().unwrap();
+        let rollup = std::sync::Arc::new(from_store.clone()).rollup(layer).await.unwrap();
+
+        // without memory, every value and every triple is spilled
+        for layer in [base, child, grandchild] {
+            convert_within(&from_store, &to_store, &work, 0, layer, None).await;
+        }
+        convert_within(&from_store, &to_store, &work, 0, rollup, Some(grandchild)).await;
+        std::fs::remove_dir_all(&work).unwrap();
+
+        let converted = to_store.get_layer(grandchild).await.unwrap().unwrap();
+        let converted_rollup = to_store.get_layer(rollup).await.unwrap().unwrap();
+        assert_same_ids(&*converted, &*converted_rollup);
+    }
+
+    #[tokio::test]
+    async fn rollup_upto_uses_the_ids_of_the_converted_layer() {
+        let work = workdir("rollup-upto-test");
+        let from_store = MemoryLayerStore::new();
+        let to_store = MemoryLayerStore::new();
+        let (base, child, grandchild) = layer_stack(&from_store).await;
+        let layer = from_store.get_layer(grandchild).await.unwrap().unwrap();
+        let rollup = from_store.rollup_upto(layer, base).await.unwrap();
+
+        for layer in [base, child, grandchild] {
+            convert(&from_store, &to_store, &work, layer, None).await;
+        }
+        convert(&from_store, &to_store, &work, rollup, Some(grandchild)).await;
+        std::fs::remove_dir_all(&work).unwrap();
+
+        let converted = to_store.get_layer(grandchild).await.unwrap().unwrap();
+        let converted_rollup = to_store.get_layer(rollup).await.unwrap().unwrap();
+        assert_eq!(Some(base), converted_rollup.parent_name());
+        assert_same_ids(&*converted, &*converted_rollup);
+    }
+
+    #[test]
+    fn outer_ids_keep_their_order_when_inner_ids_collapse() {
+        let idmap = [3, 1, 4, 2];
+        // inner 2 and 4 collapse into new inner 1, inner 1 moves to 2
+        let new_inner = |inner: u64| [2, 1, 3, 1][inner as usize - 1];
+        let outer_remap = compact_outer_ids(&idmap[..], new_inner, 3);
+        assert_eq!(vec![1, 1, 2, 3], outer_remap);
+        assert_eq!(Some(vec![1, 2, 3]), remap_idmap(&idmap[..], &outer_remap, new_inner, 3));
+    }
+
+    #[test]
+    fn id_map_that_is_not_a_permutation_is_rejected() {
+        let idmap = [3, 1, 4, 2];
+        let new_inner = |inner: u64| [2, 1, 3, 1][inner as usize - 1];
+        // outer ids that do not collapse along with their inner ids
+        assert_eq!(None, remap_idmap(&idmap[..], &[1, 2, 3, 4], new_inner, 3));
+        // outer ids outside of the layer
+        assert_eq!(None, remap_idmap(&idmap[..], &[1, 1, 2], new_inner, 3));
+    }
+
+    #[tokio::test]
+    async fn child_layer_with_an_id_map_is_converted_on_its_own() {
+        let work = workdir("idmap-test");
+        let from_store = MemoryLayerStore::new();
+        let to_store = MemoryLayerStore::new();
+        let (base, child, grandchild) = layer_stack(&from_store).await;
+        let layer = from_store.get_layer(grandchild).await.unwrap().unwrap();
+        let rollup = from_store.rollup_upto(layer, base).await.unwrap();
+
+        for layer in [base, child, grandchild] {
+            convert(&from_store, &to_store, &work, layer, None).await;
+        }
+        // as `convert-layer` does, without the layer it rolls up
+        convert(&from_store, &to_store, &work, rollup, None).await;
+        std::fs::remove_dir_all(&work).unwrap();
+
+        let converted = to_store.get_layer(grandchild).await.unwrap().unwrap();
+        let converted_rollup = to_store.get_layer(rollup).await.unwrap().unwrap();
+        assert_eq!(Some(base), converted_rollup.parent_name());
+        assert_eq!(string_triples(&*converted), string_triples(&*converted_rollup));
+        let object = converted_rollup
+            .object_value_id(&String::make_entry(&"aB"))
+            .unwrap();
+        assert_eq!(2, converted_rollup.triples_o(object).count());
+        // `z` and `b` swapped places, and keep their old order as outer ids
+        let z = converted_rollup.object_value_id(&String::make_entry(&"z")).unwrap();
+        let b = converted_rollup.object_value_id(&String::make_entry(&"b")).unwrap();
+        assert!(z < b);
+    }
+
+    #[tokio::test]
+    async fn failed_layer_conversion_keeps_the_converted_layer() {
+        let dir = |name: &str| {
+            let path = std::env::temp_dir().join(format!("escape-fixup-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let (from, to) = (dir("convert-layer-from"), dir("convert-layer-to"));
+        let work = format!("{to}/.workdir");
+        let from_store = DirectoryLayerStore::new(&from);
+        let to_store = DirectoryLayerStore::new(&to);
+
+        let mut builder = from_store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "moo\\tmoo"));
+        builder.commit_boxed().await.unwrap();
+        let mut builder = from_store.create_child_layer(base).await.unwrap();
+        let child = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "\\\"quack\\\""));
+        builder.commit_boxed().await.unwrap();
+
+        // every layer was written before the cutoff, so it is converted
+        let cutoff = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+        let options = ConversionOptions::new(&work, cutoff).layout(StoreLayout::Directory);
+        for id in [base, child] {
+            convert_layer(&from, &to, &options, &name_to_string(id)).await.unwrap();
+        }
+        // as after a store conversion, which removes the parent maps
+        // nothing reads anymore
+        remove_parent_map(&work, base).await.unwrap();
+
+        let result = convert_layer(&from, &to, &options, &name_to_string(child)).await;
+        assert!(result.is_err());
+        let converted = to_store.get_layer(child).await.unwrap().unwrap();
+        assert!(converted.value_triple_exists(&ValueTriple::new_string_value("duck", "says", "\"quack\"")));
+        assert!(!std::path::Path::new(&staging_path(&to)).exists());
+
+        // a base layer needs no parent map, so it is replaced
+        convert_layer(&from, &to, &options, &name_to_string(base)).await.unwrap();
+        let converted = to_store.get_layer(base).await.unwrap().unwrap();
+        assert!(converted.value_triple_exists(&ValueTriple::new_string_value("cow", "says", "moo\tmoo")));
+
+        let result = convert_layer(&from, &to, &options, "not a layer").await;
+        assert!(matches!(result, Err(StoreConversionError::Io(e)) if e.kind() == io::ErrorKind::InvalidInput));
+
+        for dir in [from, to] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
     }
 }
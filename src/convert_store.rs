@@ -1,20 +1,32 @@
+use async_trait::async_trait;
 use chrono::Local;
 use terminus_store::storage::archive::ArchiveLayerStore;
-use terminus_store::storage::directory::DirectoryLabelStore;
+use terminus_store::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
 use terminus_store::storage::name_to_string;
 use terminus_store::storage::string_to_name;
+use terminus_store::storage::{LabelStore, LayerStore, PersistentLayerStore};
 use tokio::fs::OpenOptions;
-use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::io::BufReader;
+use tokio::task::JoinSet;
 
+use crate::convert_dictionary::EscapeFallback;
 use crate::convert_layer::*;
+use crate::dataconversion::MalformedEscapePolicy;
+use crate::interrupt::Interrupt;
+use crate::layout::StoreLayout;
+use crate::lock::{DirectoryLock, LockError, LOCK_FILE};
+use crate::observer::{ConversionObserver, ConversionWarning, LayerAction, PrintObserver, Removal};
+use crate::preflight::*;
 use crate::reachable::*;
+use crate::staging::StagedArchiveLayerStore;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use tokio::fs;
 
@@ -26,125 +38,616 @@ pub enum StoreConversionError {
     LayerConversion(#[from] LayerConversionError),
     #[error("Some layer conversions failed")]
     LayerConversionsFailed(Vec<[u32; 5]>),
+    #[error("source store has storage version {found}, but version {expected} was expected. It may have been converted already, use --force to convert it anyway")]
+    UnexpectedStorageVersion { found: u64, expected: u64 },
+    #[error("target `{0}` is not empty, and is not a previous conversion that can be resumed")]
+    TargetNotEmpty(String),
+    Lock(#[from] LockError),
+    #[error("not enough free space, {}. Use --force to convert anyway", .0.iter().map(|shortage| shortage.to_string()).collect::<Vec<_>>().join(", "))]
+    InsufficientSpace(Vec<SpaceShortage>),
+    #[error("The conversion was interrupted")]
+    Interrupted,
     Io(#[from] io::Error),
 }
 
+/// The default for [`ConversionOptions::memory_limit`].
+pub const DEFAULT_MEMORY_LIMIT: usize = 1 << 30;
+
+/// How to convert a store. Only the workdir and the cutoff are
+/// required, everything else has a default that can be changed with
+/// the builder methods.
+#[derive(Clone)]
+pub struct ConversionOptions {
+    pub(crate) work: String,
+    pub(crate) cutoff: SystemTime,
+    pub(crate) keep_going: bool,
+    pub(crate) verbose: bool,
+    pub(crate) replace: bool,
+    pub(crate) clean: bool,
+    pub(crate) policy: MalformedEscapePolicy,
+    pub(crate) reverse: bool,
+    pub(crate) jobs: usize,
+    pub(crate) layout: StoreLayout,
+    pub(crate) force: bool,
+    pub(crate) memory_limit: usize,
+    interrupt: Option<Interrupt>,
+    observer: Option<Arc<dyn ConversionObserver>>,
+    creation_times: Option<Arc<dyn LayerCreationTimes>>,
+}
+
+impl ConversionOptions {
+    /// Options for a conversion that keeps its mappings in `work`.
+    /// Layers created after `cutoff` are copied instead of converted,
+    /// unless the conversion is reversed.
+    pub fn new(work: &str, cutoff: SystemTime) -> Self {
+        Self {
+            work: work.to_string(),
+            cutoff,
+            keep_going: false,
+            verbose: false,
+            replace: false,
+            clean: false,
+            policy: MalformedEscapePolicy::default(),
+            reverse: false,
+            jobs: 1,
+            layout: StoreLayout::default(),
+            force: false,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            interrupt: None,
+            observer: None,
+            creation_times: None,
+        }
+    }
+
+    /// Keep going with other layers if a layer does not convert.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Replace the original store with the converted store once done.
+    pub fn replace(mut self, replace: bool) -> Self {
+        self.replace = replace;
+        self
+    }
+
+    /// Remove the workdir once done.
+    pub fn clean(mut self, clean: bool) -> Self {
+        self.clean = clean;
+        self
+    }
+
+    /// What to do with escape sequences that cannot be decoded.
+    pub fn policy(mut self, policy: MalformedEscapePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Convert a version 11 store back into a version 10 store.
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    /// How many layers to convert at the same time.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// How the layers of the original store are laid out on disk. The
+    /// converted store gets the same layout.
+    pub fn layout(mut self, layout: StoreLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Convert the store even if its storage version says it does not
+    /// need converting, or if it does not seem to fit on disk.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// How much memory to use for sorting the entries of value
+    /// dictionaries before spilling them to the workdir. The limit is
+    /// shared between the layers converted at the same time.
+    pub fn memory_limit(mut self, memory_limit: usize) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Lets the conversion be interrupted, leaving a journal it can be
+    /// resumed from.
+    pub fn interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Where to report progress to. Without an observer, progress is
+    /// printed to standard output.
+    pub fn observer(mut self, observer: Arc<dyn ConversionObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Where to take the creation times of layers from, which are
+    /// compared with the cutoff. Without it, they are the creation
+    /// times of the layer files in the original store.
+    pub fn creation_times(mut self, creation_times: Arc<dyn LayerCreationTimes>) -> Self {
+        self.creation_times = Some(creation_times);
+        self
+    }
+
+    pub(crate) fn observer_or_default(&self) -> Arc<dyn ConversionObserver> {
+        match &self.observer {
+            Some(observer) => observer.clone(),
+            None => Arc::new(PrintObserver::new(self.verbose)),
+        }
+    }
+
+    pub(crate) fn creation_times_or_default(&self, from: &str) -> Arc<dyn LayerCreationTimes> {
+        match &self.creation_times {
+            Some(creation_times) => creation_times.clone(),
+            None => Arc::new(FileCreationTimes::new(from, self.layout)),
+        }
+    }
+}
+
+/// Tells when the layers of the original store were written, which
+/// decides whether a layer is converted or copied.
+#[async_trait]
+pub trait LayerCreationTimes: Send + Sync {
+    async fn creation_time(&self, layer: [u32; 5]) -> io::Result<SystemTime>;
+}
+
+/// Takes the creation times of layers from their files on disk.
+#[derive(Debug, Clone)]
+pub struct FileCreationTimes {
+    from: String,
+    layout: StoreLayout,
+}
+
+impl FileCreationTimes {
+    pub fn new(from: &str, layout: StoreLayout) -> Self {
+        Self {
+            from: from.to_string(),
+            layout,
+        }
+    }
+}
+
+#[async_trait]
+impl LayerCreationTimes for FileCreationTimes {
+    async fn creation_time(&self, layer: [u32; 5]) -> io::Result<SystemTime> {
+        layer_creation_time(&self.from, self.layout, layer).await
+    }
+}
+
+/// Everything a single layer conversion needs, so that it can run as
+/// its own task.
+#[derive(Clone)]
+struct LayerConverter<F, T> {
+    from_store: F,
+    to_store: T,
+    options: ConversionOptions,
+    observer: Arc<dyn ConversionObserver>,
+    creation_times: Arc<dyn LayerCreationTimes>,
+}
+
+impl<F: PersistentLayerStore, T: PersistentLayerStore> LayerConverter<F, T> {
+    async fn convert(
+        self,
+        scheduled_after: Option<[u32; 5]>,
+        layer: [u32; 5],
+    ) -> io::Result<Result<Vec<EscapeFallback>, LayerConversionError>> {
+        let options = &self.options;
+        let parent = LayerStore::get_layer_parent_name(&self.from_store, layer).await?;
+        let rollup_of = scheduled_after.filter(|scheduled_after| parent != Some(*scheduled_after));
+        // every layer of a converted store was written after the
+        // cutoff, so when reversing, every layer is converted.
+        if !options.reverse && self.creation_times.creation_time(layer).await? > options.cutoff {
+            // written after the upgrade, so there is nothing to convert
+            self.observer.layer_started(layer, LayerAction::Copy);
+            return Ok(copy_layer_with_stores(
+                &self.from_store,
+                &self.to_store,
+                &options.work,
+                options.memory_limit / options.jobs,
+                &*self.observer,
+                layer,
+                rollup_of,
+            )
+            .await
+            .map(|()| Vec::new()));
+        }
+
+        self.observer.layer_started(layer, LayerAction::Convert);
+        Ok(convert_layer_with_stores(
+            &self.from_store,
+            &self.to_store,
+            &options.work,
+            options.memory_limit / options.jobs,
+            &*self.observer,
+            options.policy,
+            options.reverse,
+            layer,
+            rollup_of,
+        )
+        .await)
+    }
+}
+
+/// Counts how many unfinished layers still read the parent map of
+/// every layer, so that parent maps can be removed from the workdir as
+/// soon as nothing reads them anymore. This keeps the workdir
+/// proportional to the layers in progress rather than to the history.
+struct ParentMapReaders {
+    /// the parent maps that every unfinished layer reads
+    reads: HashMap<[u32; 5], Vec<[u32; 5]>>,
+    /// how many unfinished layers read the parent map of a layer
+    readers: HashMap<[u32; 5], usize>,
+}
+
+impl ParentMapReaders {
+    /// Every layer reads the parent map of its parent. A rollup also
+    /// reads the parent map of the layer it rolls up, which is the
+    /// layer it was scheduled after.
+    async fn new<S: PersistentLayerStore>(
+        store: &S,
+        reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+        completed: &HashSet<[u32; 5]>,
+    ) -> io::Result<Self> {
+        let mut reads = HashMap::new();
+        for (scheduled_after, layers) in reachable.iter() {
+            for layer in layers.iter().filter(|layer| !completed.contains(*layer)) {
+                let parent = LayerStore::get_layer_parent_name(store, *layer).await?;
+                let rolled_up = scheduled_after.filter(|scheduled_after| parent != Some(*scheduled_after));
+                reads.insert(*layer, parent.into_iter().chain(rolled_up).collect());
+            }
+        }
+
+        Ok(Self::from_reads(reads))
+    }
+
+    fn from_reads(reads: HashMap<[u32; 5], Vec<[u32; 5]>>) -> Self {
+        let mut readers = HashMap::new();
+        for read in reads.values().flatten() {
+            *readers.entry(*read).or_insert(0) += 1;
+        }
+
+        Self { reads, readers }
+    }
+
+    fn is_read(&self, layer: [u32; 5]) -> bool {
+        self.readers.contains_key(&layer)
+    }
+
+    /// Record that `layer` completed, returning the layers whose parent
+    /// map is no longer read, including `layer` itself if nothing
+    /// reads its parent map.
+    fn completed(&mut self, layer: [u32; 5]) -> Vec<[u32; 5]> {
+        let mut unread = Vec::new();
+        for read in self.reads.remove(&layer).unwrap_or_default() {
+            let readers = self
+                .readers
+                .get_mut(&read)
+                .expect("parent map that is read should have readers");
+            *readers -= 1;
+            if *readers == 0 {
+                self.readers.remove(&read);
+                unread.push(read);
+            }
+        }
+        if !self.is_read(layer) {
+            unread.push(layer);
+        }
+
+        unread
+    }
+}
+
+/// What a completed conversion did with the converted store.
+#[derive(Debug, Clone, Default)]
+pub struct ConversionReport {
+    /// Where the original store was moved to, if it was replaced.
+    pub backup: Option<String>,
+}
+
+/// Convert all reachable layers of a store. Up to `jobs` layers are
+/// converted at the same time, but a layer is only started once the
+/// layer it was scheduled after has completed.
 pub async fn convert_store(
     from: &str,
     to: &str,
-    work: &str,
-    keep_going: bool,
-    verbose: bool,
-    replace: bool,
-    clean: bool,
-) -> Result<(), StoreConversionError> {
-    let v10_layer_store = ArchiveLayerStore::new(from);
-    let v10_label_store = DirectoryLabelStore::new(from);
-    let v11_layer_store = ArchiveLayerStore::new(to);
+    options: &ConversionOptions,
+) -> Result<ConversionReport, StoreConversionError> {
+    let from_labels = DirectoryLabelStore::new(from);
+    let to_labels = DirectoryLabelStore::new(to);
+    match options.layout {
+        StoreLayout::Archive => {
+            let from_store = ArchiveLayerStore::new(from);
+            // layers are built in the workdir rather than in memory
+            let to_store = StagedArchiveLayerStore::new(to, &options.work);
+            convert_store_with_stores(from_store, to_store, &from_labels, &to_labels, from, to, options).await
+        }
+        StoreLayout::Directory => {
+            let from_store = DirectoryLayerStore::new(from);
+            let to_store = DirectoryLayerStore::new(to);
+            convert_store_with_stores(from_store, to_store, &from_labels, &to_labels, from, to, options).await
+        }
+    }
+}
 
-    let reachable = find_reachable_layers(&v10_layer_store, &v10_label_store, verbose).await?;
+/// Convert all reachable layers from `from_store` into `to_store`, and
+/// the labels from `from_labels` into `to_labels`. `from` and `to` are
+/// the directories of the stores, used for the storage version, the
+/// locks and the layers that are cleaned up.
+#[allow(clippy::too_many_arguments)]
+pub async fn convert_store_with_stores<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: F,
+    to_store: T,
+    from_labels: &impl LabelStore,
+    to_labels: &impl LabelStore,
+    from: &str,
+    to: &str,
+    options: &ConversionOptions,
+) -> Result<ConversionReport, StoreConversionError> {
+    let ConversionOptions {
+        work,
+        keep_going,
+        replace,
+        clean,
+        policy,
+        reverse,
+        jobs,
+        layout,
+        force,
+        ..
+    } = options.clone();
+    let work = work.as_str();
+    let interrupt = options.interrupt.clone().unwrap_or_default();
+    let observer = options.observer_or_default();
+    check_source_version(from, reverse, force, &*observer).await?;
+    // held for the whole run, so that no one else writes to the status
+    // log or the target at the same time.
+    let work_lock = DirectoryLock::acquire(work)?;
+    let target_lock = DirectoryLock::acquire(to)?;
+    check_target(to, work).await?;
+    let reachable = find_reachable_layers(&from_store, from_labels, &*observer).await?;
 
-    let mut options = OpenOptions::new();
-    options.create(true);
-    options.write(true);
+    // appended to, so that a resumed conversion keeps what earlier
+    // runs logged.
+    let mut log_options = OpenOptions::new();
+    log_options.create(true);
+    log_options.append(true);
     let mut error_path = PathBuf::from(work);
     std::fs::create_dir_all(&error_path)?;
     error_path.push("error.log");
-    let mut error_log = options.open(error_path).await?;
+    let mut error_log = log_options.open(&error_path).await?;
+    let mut fallback_path = error_path;
+    fallback_path.set_file_name("fallback.log");
+    let mut fallback_log = log_options.open(&fallback_path).await?;
+    let mut fallback_count = 0;
     let status_hashmap = get_status_hashmap(work).await?;
-    let mut status_log = status_log(work).await?;
+    let mut completed: HashSet<[u32; 5]> = status_hashmap
+        .iter()
+        .filter(|(_, status)| matches!(status, ConversionStatus::Completed))
+        .map(|(layer, _)| *layer)
+        .collect();
+    let mut status_log = status_log(work, &*observer).await?;
+    clean_unfinished_layers(to, work, &status_hashmap, &*observer).await?;
+    let mut parent_map_readers = ParentMapReaders::new(&from_store, &reachable, &completed).await?;
+    // parent maps left behind by an earlier run that nothing reads
+    // anymore.
+    for layer in reachable.values().flatten() {
+        if completed.contains(layer) && !parent_map_readers.is_read(*layer) {
+            remove_parent_map(work, *layer).await?;
+            observer.removed(Removal::ParentMap(*layer));
+        }
+    }
+    let estimate = estimate_conversion(
+        &from_store,
+        work,
+        &reachable,
+        &completed,
+        jobs,
+        options.memory_limit,
+    )
+    .await?;
+    check_resources(to, work, &estimate, &*observer, force).await?;
+
+    let converter = LayerConverter {
+        from_store: from_store.clone(),
+        to_store: to_store.clone(),
+        options: options.clone(),
+        observer: observer.clone(),
+        creation_times: options.creation_times_or_default(from),
+    };
 
-    let mut visit_queue = Vec::new();
-    visit_queue.extend(reachable[&None].clone());
+    // every layer is queued together with the layer it was scheduled
+    // after, which is its parent unless it is a rollup.
+    let mut visit_queue: Vec<(Option<[u32; 5]>, [u32; 5])> = Vec::new();
+    visit_queue.extend(reachable[&None].iter().map(|layer| (None, *layer)));
 
     let mut failures = Vec::new();
+    // the first error, if we are not to keep going. No new layers are
+    // started after it, but the running ones get to finish.
+    let mut error = None;
+    let mut running = JoinSet::new();
+    // the layers in progress, so that they can be cleaned up if they
+    // are aborted.
+    let mut in_flight = HashSet::new();
+    let mut interruptions = 0;
 
-    while let Some(layer) = visit_queue.pop() {
-        let status = status_hashmap.get(&layer);
-        match status {
-            Some(ConversionStatus::Completed) => {
-                if verbose {
-                    println!("skipping: {}", name_to_string(layer))
-                };
+    loop {
+        while error.is_none() && interrupt.requests() == 0 && running.len() < jobs {
+            let (scheduled_after, layer) = match visit_queue.pop() {
+                Some(next) => next,
+                None => break,
+            };
+            // anything left of an earlier attempt at a layer that did
+            // not complete was removed by clean_unfinished_layers.
+            if let Some(ConversionStatus::Completed) = status_hashmap.get(&layer) {
+                observer.layer_skipped(layer);
                 // even though we skip this layer, its children still
                 // might need to be converted, so here they are added
                 // to the visit queue.
                 if let Some(children) = reachable.get(&Some(layer)) {
-                    visit_queue.extend(children.clone());
+                    visit_queue.extend(children.iter().map(|child| (Some(layer), *child)));
                 }
                 continue;
             }
-            Some(_) => layer_cleanup(to, layer).await?,
-            None => (),
+            // status.log is only ever written from here, so that
+            // concurrent conversions do not interleave their records.
+            write_status(&mut status_log, layer, ConversionStatus::Started).await?;
+            let converter = converter.clone();
+            in_flight.insert(layer);
+            running.spawn(async move {
+                let result = converter.convert(scheduled_after, layer).await;
+                (layer, result)
+            });
         }
-        write_status(&mut status_log, layer, ConversionStatus::Started).await?;
-        let result = convert_layer_with_stores(
-            &v10_layer_store,
-            &v11_layer_store,
-            work,
-            verbose,
-            layer,
-        )
-        .await;
-        if let Ok(()) = result {
-            write_status(&mut status_log, layer, ConversionStatus::Completed).await?;
-            if let Some(children) = reachable.get(&Some(layer)) {
-                visit_queue.extend(children.clone());
+
+        // an interruption is handled before any layer that completed,
+        // so that a second one aborts the layers in progress right away.
+        let joined = tokio::select! {
+            biased;
+            _ = interrupt.requested_after(interruptions) => {
+                interruptions = interrupt.requests();
+                if interruptions > 1 {
+                    running.abort_all();
+                }
+                continue;
             }
-        } else if let Err(e) = result {
-            write_status(&mut status_log, layer, ConversionStatus::Error).await?;
-            eprintln!("ERROR: {e}");
+            joined = running.join_next() => joined,
+        };
+        let (layer, result) = match joined {
+            Some(Ok(joined)) => joined,
+            Some(Err(e)) if e.is_cancelled() => continue,
+            Some(Err(e)) => return Err(io::Error::other(e).into()),
+            None => break,
+        };
+        in_flight.remove(&layer);
+        match result? {
+            Ok(fallbacks) => {
+                for fallback in fallbacks.iter() {
+                    fallback_log
+                        .write_all(format!("{} {fallback}\n", name_to_string(layer)).as_bytes())
+                        .await?;
+                }
+                fallback_log.flush().await?;
+                fallback_count += fallbacks.len();
+                observer.layer_finished(layer, &fallbacks);
+                write_status(&mut status_log, layer, ConversionStatus::Completed).await?;
+                completed.insert(layer);
+                for unread in parent_map_readers.completed(layer) {
+                    remove_parent_map(work, unread).await?;
+                    observer.removed(Removal::ParentMap(unread));
+                }
+                if let Some(children) = reachable.get(&Some(layer)) {
+                    visit_queue.extend(children.iter().map(|child| (Some(layer), *child)));
+                }
+            }
+            Err(e) => {
+                write_status(&mut status_log, layer, ConversionStatus::Error).await?;
+                observer.layer_failed(layer, &e);
+                error_log.write_all(e.to_string().as_bytes()).await?;
+                error_log.write_all(b"\n").await?;
+                error_log.flush().await?;
+                if keep_going {
+                    failures.push(layer);
+                } else if error.is_none() {
+                    error = Some(e);
+                }
+            }
+        }
+    }
+
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+
+    if interrupt.requests() != 0 {
+        for layer in in_flight {
+            layer_cleanup(to, work, layout, layer, &*observer).await?;
+            write_status(&mut status_log, layer, ConversionStatus::Interrupted).await?;
+        }
+        return Err(StoreConversionError::Interrupted);
+    }
+
+    // rollups can only be linked once both the rolled up layer and
+    // the rollup itself are converted.
+    for layer in reachable.values().flatten() {
+        let is_converted = |layer| completed.contains(&layer);
+        if let Err(e) = copy_rollup_file(&from_store, &to_store, *layer, is_converted).await {
+            observer.layer_failed(*layer, &e);
             error_log.write_all(e.to_string().as_bytes()).await?;
             error_log.write_all(b"\n").await?;
             error_log.flush().await?;
             if keep_going {
-                failures.push(layer);
+                failures.push(*layer);
             } else {
                 return Err(e.into());
             }
         }
     }
 
-    convert_labels(from, to).await?;
-    write_version_file(to).await?;
+    if fallback_count != 0 {
+        observer.fallbacks_applied(policy, fallback_count, &fallback_path);
+    }
+
+    convert_labels(from_labels, to_labels).await?;
+    let version = if reverse {
+        V10_STORAGE_VERSION
+    } else {
+        V11_STORAGE_VERSION
+    };
+    write_version_file(to, version).await?;
 
     if !failures.is_empty() {
         Err(StoreConversionError::LayerConversionsFailed(failures))
     } else {
+        // the lock file is removed with the workdir, but the lock is
+        // held until then.
         if clean {
             clean_workdir(work).await?;
-            if verbose {
-                println!("Workdir `{work}` removed");
-            }
+            observer.removed(Removal::Workdir(work));
         }
-        if replace {
-            let backup_path = replace_storage_directory(from, to).await?;
-            println!("Version 11 Store now available");
-            println!("Backup storage directory is in `{backup_path}`");
+        drop(work_lock);
+        drop(target_lock);
+        let backup = if replace {
+            Some(replace_storage_directory(from, to).await?)
         } else {
-            println!("Your version 11 Store is converted in `{to}`, you will need to manually move it to the target storage location: `{from}`");
-        }
-        println!("Conversion completed!");
-        if !clean {
-            println!("You can now remove your workdir: `{work}`");
-        }
-        Ok(())
+            None
+        };
+        Ok(ConversionReport { backup })
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConversionStatus {
     Error,
     Completed,
     Started,
+    /// The layer was aborted while in progress.
+    Interrupted,
 }
 
-impl ToString for ConversionStatus {
-    fn to_string(&self) -> String {
-        match self {
-            ConversionStatus::Error => "Error".to_string(),
-            ConversionStatus::Completed => "Completed".to_string(),
-            ConversionStatus::Started => "Started".to_string(),
-        }
+impl fmt::Display for ConversionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConversionStatus::Error => "Error",
+            ConversionStatus::Completed => "Completed",
+            ConversionStatus::Started => "Started",
+            ConversionStatus::Interrupted => "Interrupted",
+        };
+        f.write_str(name)
     }
 }
 
@@ -156,6 +659,7 @@ impl FromStr for ConversionStatus {
             "Error" => Ok(ConversionStatus::Error),
             "Completed" => Ok(ConversionStatus::Completed),
             "Started" => Ok(ConversionStatus::Started),
+            "Interrupted" => Ok(ConversionStatus::Interrupted),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Unknown conversion status encountered",
@@ -164,34 +668,95 @@ impl FromStr for ConversionStatus {
     }
 }
 
-pub async fn get_status_hashmap(work: &str) -> io::Result<HashMap<[u32; 5], ConversionStatus>> {
-    let mut status_options = OpenOptions::new();
-    status_options.read(true);
-    status_options.create(false);
-    let mut status_path = PathBuf::from(work);
-    status_path.push("status.log");
-    let status_log = status_options.open(status_path).await;
-    match status_log {
-        Ok(status_log) => {
-            let mut hashmap = HashMap::new();
-            let status_log_buf = BufReader::new(status_log);
-            let mut lines = status_log_buf.lines();
-            while let Some(line) = lines.next_line().await? {
-                let elts = line.split(' ').collect::<Vec<&str>>();
-                let layer = string_to_name(elts[0])?;
-                let status = ConversionStatus::from_str(elts[1])?;
-                hashmap.insert(layer, status);
-            }
-            Ok(hashmap)
+/// The status journal of a conversion, as read back from `status.log`.
+#[derive(Debug, Default)]
+pub struct StatusJournal {
+    /// The last recorded status of every layer.
+    pub statuses: HashMap<[u32; 5], ConversionStatus>,
+    /// The number of intact records.
+    pub records: usize,
+    /// Records at the end of the journal that were torn or corrupt,
+    /// for instance by a power loss while writing them.
+    pub torn_records: usize,
+    /// The length of the intact part of the journal.
+    valid_len: u64,
+}
+
+fn status_path(work: &str) -> PathBuf {
+    let mut path = PathBuf::from(work);
+    path.push("status.log");
+    path
+}
+
+fn record_checksum(record: &str) -> u32 {
+    crc32fast::hash(record.as_bytes())
+}
+
+/// Parse a journal record, which is a layer name, its status and the
+/// checksum of the two. Records written before the journal had
+/// checksums are accepted as well.
+fn parse_record(line: &[u8]) -> Option<([u32; 5], ConversionStatus)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (record, checksum) = match line.rsplit_once(' ') {
+        Some((record, checksum)) if record.contains(' ') => (record, Some(checksum)),
+        _ => (line, None),
+    };
+    if let Some(checksum) = checksum {
+        if u32::from_str_radix(checksum, 16).ok()? != record_checksum(record) {
+            return None;
         }
-        Err(e) => {
-            if e.kind() == io::ErrorKind::NotFound {
-                Ok(HashMap::with_capacity(0))
-            } else {
-                Err(e)
+    }
+    let (layer, status) = record.split_once(' ')?;
+
+    Some((string_to_name(layer).ok()?, status.parse().ok()?))
+}
+
+/// Read the status journal in `work`. Torn or corrupt records at the
+/// end of the journal are counted and skipped, but a corrupt record
+/// followed by intact ones is an error, as the journal cannot be
+/// trusted then.
+pub async fn read_status_journal(work: &str) -> io::Result<StatusJournal> {
+    let path = status_path(work);
+    let contents = match fs::read(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(StatusJournal::default()),
+        Err(e) => return Err(e),
+    };
+
+    let mut journal = StatusJournal::default();
+    let mut offset = 0;
+    let mut first_bad_line = None;
+    for (ix, line) in contents.split_inclusive(|b| *b == b'\n').enumerate() {
+        offset += line.len();
+        let record = match line.strip_suffix(b"\n") {
+            Some(line) => parse_record(line),
+            // a record without a newline was never completely written
+            None => None,
+        };
+        match (record, first_bad_line) {
+            (Some(_), Some(bad_line)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt record on line {} of `{}`", bad_line + 1, path.display()),
+                ))
+            }
+            (Some((layer, status)), None) => {
+                journal.statuses.insert(layer, status);
+                journal.records += 1;
+                journal.valid_len = offset as u64;
+            }
+            (None, _) => {
+                first_bad_line.get_or_insert(ix);
+                journal.torn_records += 1;
             }
         }
     }
+
+    Ok(journal)
+}
+
+pub async fn get_status_hashmap(work: &str) -> io::Result<HashMap<[u32; 5], ConversionStatus>> {
+    Ok(read_status_journal(work).await?.statuses)
 }
 
 pub async fn write_status(
@@ -199,74 +764,257 @@ pub async fn write_status(
     layer: [u32; 5],
     s: ConversionStatus,
 ) -> Result<(), io::Error> {
-    f.write_all(format!("{} {}\n", name_to_string(layer), s.to_string(),).as_bytes())
+    let record = format!("{} {s}", name_to_string(layer));
+    let checksum = record_checksum(&record);
+    f.write_all(format!("{record} {checksum:08x}\n").as_bytes())
         .await?;
     f.flush().await?;
-    Ok(())
+    // a status has to survive a crash, or a resumed run would redo or
+    // skip the wrong layers.
+    f.sync_data().await
 }
 
-pub async fn status_log(work: &str) -> io::Result<fs::File> {
+/// Open the status journal in `work` for appending. Torn records at
+/// the end of the journal are removed first, so that new records do
+/// not end up on the same line.
+pub async fn status_log(work: &str, observer: &dyn ConversionObserver) -> io::Result<fs::File> {
+    std::fs::create_dir_all(work)?;
+    let journal = read_status_journal(work).await?;
+    let path = status_path(work);
+    if journal.torn_records != 0 {
+        observer.warning(&ConversionWarning::TornRecordsRemoved {
+            records: journal.torn_records,
+            path: path.clone(),
+        });
+        let file = OpenOptions::new().write(true).open(&path).await?;
+        file.set_len(journal.valid_len).await?;
+        file.sync_all().await?;
+    }
+
     let mut completed_options = OpenOptions::new();
     completed_options.create(true);
     completed_options.append(true);
-    let mut completed_path = PathBuf::from(work);
-    std::fs::create_dir_all(&completed_path)?;
-    completed_path.push("status.log");
-    let completed_log = completed_options.open(completed_path).await?;
+    let completed_log = completed_options.open(path).await?;
     Ok(completed_log)
 }
 
-pub async fn convert_labels(from: &str, to: &str) -> io::Result<()> {
-    let v11_store_path = PathBuf::from(to);
-    let mut stream = fs::read_dir(from).await?;
-    while let Some(direntry) = stream.next_entry().await? {
-        if direntry.file_type().await?.is_file() {
-            let os_name = direntry.file_name();
-            let name = os_name.to_str().ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "unexpected non-utf8 directory name",
-                )
-            })?;
-            if name.ends_with(".label") {
-                let mut to_path = v11_store_path.clone();
-                to_path.push(name);
-                fs::copy(direntry.path(), to_path).await?;
-            }
+/// Point every label of `to` at the same layer as in `from`. Converted
+/// layers keep their names, so nothing needs mapping. Labels that are
+/// set already, by a run that did not complete, are left alone.
+pub async fn convert_labels(from: &impl LabelStore, to: &impl LabelStore) -> io::Result<()> {
+    for label in from.labels().await? {
+        let converted = match to.get_label(&label.name).await? {
+            Some(converted) => converted,
+            None => to.create_label(&label.name).await?,
+        };
+        if converted.layer == label.layer {
+            continue;
+        }
+        if to.set_label_option(&converted, label.layer).await?.is_none() {
+            return Err(io::Error::other(format!(
+                "label `{}` was changed while it was converted",
+                label.name
+            )));
         }
     }
 
     Ok(())
 }
 
-pub async fn layer_cleanup(to: &str, layer: [u32; 5]) -> Result<(), io::Error> {
-    let name = name_to_string(layer);
-    println!("layer cleanup: {name}");
-    let larch = format!("{name}.larch");
-    let rollup = format!("{name}.rollup.hex");
-    let prefix = &name[..3];
-    let mut path = PathBuf::from(to);
-    path.push(prefix);
-    let mut rollup_path = path.clone();
-    rollup_path.push(rollup);
-    let mut larch_path = path;
-    larch_path.push(larch);
-    let rollup_res = tokio::fs::remove_file(rollup_path).await;
-    let larch_res = tokio::fs::remove_file(larch_path).await;
-    if let Err(e) = rollup_res {
-        if e.kind() != io::ErrorKind::NotFound {
-            return Err(e);
+pub async fn layer_creation_time(
+    from: &str,
+    layout: StoreLayout,
+    layer: [u32; 5],
+) -> io::Result<SystemTime> {
+    let metadata = fs::metadata(layout.layer_path(from, layer)).await?;
+    // not every filesystem records creation times
+    metadata.created().or_else(|_| metadata.modified())
+}
+
+pub async fn layer_cleanup(
+    to: &str,
+    work: &str,
+    layout: StoreLayout,
+    layer: [u32; 5],
+    observer: &dyn ConversionObserver,
+) -> Result<(), io::Error> {
+    observer.removed(Removal::Layer(layer));
+    layout.remove_layer(to, layer).await?;
+    // layers of an archive store are built in the workdir
+    StoreLayout::Directory.remove_layer(work, layer).await
+}
+
+/// Remove everything an interrupted or failed conversion may have left
+/// behind: the layer files in the target store and the parent maps in
+/// the workdir of every layer that was not completed, as well as
+/// temporary files. Every removed path is reported to `observer`.
+/// Returns the number of removed paths.
+pub async fn clean_unfinished_layers(
+    to: &str,
+    work: &str,
+    statuses: &HashMap<[u32; 5], ConversionStatus>,
+    observer: &dyn ConversionObserver,
+) -> io::Result<usize> {
+    let mut removed = 0;
+    for dir in [to, work] {
+        let mut prefixes = match fs::read_dir(dir).await {
+            Ok(prefixes) => prefixes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(prefix) = prefixes.next_entry().await? {
+            if !is_prefix_dir(&prefix).await? {
+                continue;
+            }
+            let mut entries = fs::read_dir(prefix.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                // layer files, layer directories and parent maps all
+                // start with the name of their layer.
+                let layer = name.get(..40).and_then(|name| string_to_name(name).ok());
+                let is_unfinished = layer
+                    .map(|layer| !matches!(statuses.get(&layer), Some(ConversionStatus::Completed)))
+                    .unwrap_or(false);
+                if !is_unfinished && !name.ends_with(".tmp") {
+                    continue;
+                }
+
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    fs::remove_dir_all(&path).await?;
+                } else {
+                    fs::remove_file(&path).await?;
+                }
+                observer.removed(Removal::Unfinished(&path));
+                removed += 1;
+            }
         }
     }
-    if let Err(e) = larch_res {
-        if e.kind() != io::ErrorKind::NotFound {
-            return Err(e);
+
+    Ok(removed)
+}
+
+async fn is_prefix_dir(entry: &fs::DirEntry) -> io::Result<bool> {
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    let is_prefix = name.len() == 3 && name.chars().all(|c| c.is_ascii_hexdigit());
+
+    Ok(is_prefix && entry.file_type().await?.is_dir())
+}
+
+/// The storage version of a store written by TerminusDB 10.
+pub const V10_STORAGE_VERSION: u64 = 1;
+/// The storage version of a store with unescaped strings, as read by
+/// TerminusDB 11.
+pub const V11_STORAGE_VERSION: u64 = 2;
+
+/// Read the storage version of the store at `from`. A store without a
+/// version file predates storage versions, so it is a version 10 store.
+pub async fn read_version_file(from: &str) -> io::Result<u64> {
+    let mut path = PathBuf::from(from);
+    path.push("STORAGE_VERSION");
+    match fs::read_to_string(path).await {
+        Ok(contents) => contents.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid storage version `{}`", contents.trim()),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(V10_STORAGE_VERSION),
+        Err(e) => Err(e),
+    }
+}
+
+/// Refuse to convert a store that does not have the storage version
+/// the conversion expects, as converting a store twice corrupts its
+/// strings. With `force`, only warn about it.
+pub async fn check_source_version(
+    from: &str,
+    reverse: bool,
+    force: bool,
+    observer: &dyn ConversionObserver,
+) -> Result<(), StoreConversionError> {
+    let expected = if reverse {
+        V11_STORAGE_VERSION
+    } else {
+        V10_STORAGE_VERSION
+    };
+    let found = read_version_file(from).await?;
+    if found == expected {
+        return Ok(());
+    }
+    if !force {
+        return Err(StoreConversionError::UnexpectedStorageVersion { found, expected });
+    }
+    observer.warning(&ConversionWarning::UnexpectedStorageVersion { found, expected });
+    Ok(())
+}
+
+/// Refuse to start a conversion that is not expected to fit on the
+/// filesystems of the target and the workdir, rather than running out
+/// of space halfway. With `force`, only warn about it.
+pub async fn check_resources(
+    to: &str,
+    work: &str,
+    estimate: &ConversionEstimate,
+    observer: &dyn ConversionObserver,
+    force: bool,
+) -> Result<(), StoreConversionError> {
+    observer.estimated(estimate);
+    if let Some(available) = available_memory().filter(|available| estimate.memory > *available) {
+        observer.warning(&ConversionWarning::InsufficientMemory {
+            needed: estimate.memory,
+            available,
+        });
+    }
+
+    let shortages = check_space(to, work, estimate).await?;
+    if shortages.is_empty() {
+        return Ok(());
+    }
+    if !force {
+        return Err(StoreConversionError::InsufficientSpace(shortages));
+    }
+    for shortage in shortages {
+        observer.warning(&ConversionWarning::InsufficientSpace(shortage));
+    }
+    Ok(())
+}
+
+/// Make sure we do not write into a directory that holds something
+/// else. The target has to be empty, apart from the workdir and the
+/// lock file, unless the workdir has the status log of an earlier run
+/// to resume.
+pub async fn check_target(to: &str, work: &str) -> Result<(), StoreConversionError> {
+    let mut status_path = PathBuf::from(work);
+    status_path.push("status.log");
+    match fs::metadata(status_path).await {
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut entries = match fs::read_dir(to).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let work = fs::canonicalize(work).await.ok();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name() == LOCK_FILE {
+            continue;
+        }
+        if work.is_some() && fs::canonicalize(entry.path()).await.ok() == work {
+            continue;
         }
+        return Err(StoreConversionError::TargetNotEmpty(to.to_string()));
     }
+
     Ok(())
 }
 
-pub async fn write_version_file(to: &str) -> Result<(), io::Error> {
+pub async fn write_version_file(to: &str, version: u64) -> Result<(), io::Error> {
     let mut options = OpenOptions::new();
     options.create(true);
     options.write(true);
@@ -274,7 +1022,7 @@ pub async fn write_version_file(to: &str) -> Result<(), io::Error> {
     let mut path = PathBuf::from(to);
     path.push("STORAGE_VERSION");
     let mut file = options.open(path).await?;
-    file.write_all(b"2").await?;
+    file.write_all(version.to_string().as_bytes()).await?;
     file.flush().await
 }
 
@@ -286,7 +1034,330 @@ pub async fn replace_storage_directory(from: &str, to: &str) -> Result<String, i
     Ok(backup)
 }
 
+/// Undo [`replace_storage_directory`], moving the converted store at
+/// `from` to `to` and the backup back to `from`.
+pub async fn restore_storage_directory(from: &str, backup: &str, to: &str) -> Result<(), io::Error> {
+    fs::rename(from, to).await?;
+    fs::rename(backup, from).await?;
+    Ok(())
+}
+
 pub async fn clean_workdir(work: &str) -> Result<(), io::Error> {
     fs::remove_dir_all(work).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::layer::{Layer, ValueTriple};
+    use terminus_store::storage::memory::{MemoryLabelStore, MemoryLayerStore};
+
+    const LAYER1: [u32; 5] = [1, 2, 3, 4, 5];
+    const LAYER2: [u32; 5] = [5, 4, 3, 2, 1];
+
+    fn workdir(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("escape-fixup-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    async fn write_journal(work: &str) {
+        let mut log = status_log(work, &PrintObserver::default()).await.unwrap();
+        write_status(&mut log, LAYER1, ConversionStatus::Started).await.unwrap();
+        write_status(&mut log, LAYER1, ConversionStatus::Completed).await.unwrap();
+        write_status(&mut log, LAYER2, ConversionStatus::Started).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn journal_round_trip() {
+        let work = workdir("journal-round-trip");
+        write_journal(&work).await;
+
+        let journal = read_status_journal(&work).await.unwrap();
+        assert_eq!(3, journal.records);
+        assert_eq!(0, journal.torn_records);
+        assert_eq!(Some(&ConversionStatus::Completed), journal.statuses.get(&LAYER1));
+        assert_eq!(Some(&ConversionStatus::Started), journal.statuses.get(&LAYER2));
+        std::fs::remove_dir_all(work).unwrap();
+    }
+
+    #[tokio::test]
+    async fn torn_trailing_record_is_skipped_and_removed() {
+        let work = workdir("journal-torn");
+        write_journal(&work).await;
+        let path = status_path(&work);
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.truncate(contents.len() - 4);
+        std::fs::write(&path, &contents).unwrap();
+
+        let journal = read_status_journal(&work).await.unwrap();
+        assert_eq!(2, journal.records);
+        assert_eq!(1, journal.torn_records);
+        assert_eq!(None, journal.statuses.get(&LAYER2));
+
+        let mut log = status_log(&work, &PrintObserver::default()).await.unwrap();
+        write_status(&mut log, LAYER2, ConversionStatus::Completed).await.unwrap();
+        let journal = read_status_journal(&work).await.unwrap();
+        assert_eq!(3, journal.records);
+        assert_eq!(0, journal.torn_records);
+        assert_eq!(Some(&ConversionStatus::Completed), journal.statuses.get(&LAYER2));
+        std::fs::remove_dir_all(work).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_record_before_intact_records_is_an_error() {
+        let work = workdir("journal-corrupt");
+        write_journal(&work).await;
+        let path = status_path(&work);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replacen("Started", "Stopped", 1)).unwrap();
+
+        let e = read_status_journal(&work).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        std::fs::remove_dir_all(work).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unfinished_layers_are_cleaned_up() {
+        let to = workdir("cleanup");
+        let work = format!("{to}/.workdir");
+        let completed = name_to_string(LAYER1);
+        let unfinished = name_to_string(LAYER2);
+        let paths = [
+            format!("{to}/{}/{completed}.larch", &completed[..3]),
+            format!("{to}/{}/{unfinished}.larch", &unfinished[..3]),
+            format!("{to}/{}/{unfinished}.rollup.hex", &unfinished[..3]),
+            format!("{work}/{}/{completed}.idmap", &completed[..3]),
+            format!("{work}/{}/{unfinished}.idmap", &unfinished[..3]),
+            format!("{work}/{}/{completed}.idmap.tmp", &completed[..3]),
+        ];
+        for path in paths.iter() {
+            let path = PathBuf::from(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        std::fs::create_dir_all(format!("{to}/{}/{unfinished}", &unfinished[..3])).unwrap();
+        let statuses = HashMap::from([
+            (LAYER1, ConversionStatus::Completed),
+            (LAYER2, ConversionStatus::Started),
+        ]);
+
+        assert_eq!(5, clean_unfinished_layers(&to, &work, &statuses, &PrintObserver::default()).await.unwrap());
+        assert!(PathBuf::from(&paths[0]).exists());
+        assert!(PathBuf::from(&paths[3]).exists());
+        assert!(!paths[1..3].iter().chain(&paths[4..]).any(|path| PathBuf::from(path).exists()));
+        std::fs::remove_dir_all(to).unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_without_checksum_are_accepted() {
+        let work = workdir("journal-legacy");
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::write(
+            status_path(&work),
+            format!("{} Started\n{} Completed\n", name_to_string(LAYER1), name_to_string(LAYER1)),
+        )
+        .unwrap();
+
+        let journal = read_status_journal(&work).await.unwrap();
+        assert_eq!(2, journal.records);
+        assert_eq!(Some(&ConversionStatus::Completed), journal.statuses.get(&LAYER1));
+        std::fs::remove_dir_all(work).unwrap();
+    }
+
+    /// Creation times that say every layer in `copied` was written
+    /// after the cutoff, and every other layer before it.
+    struct CopiedAfterCutoff {
+        cutoff: SystemTime,
+        copied: HashSet<[u32; 5]>,
+    }
+
+    #[async_trait]
+    impl LayerCreationTimes for CopiedAfterCutoff {
+        async fn creation_time(&self, layer: [u32; 5]) -> io::Result<SystemTime> {
+            if self.copied.contains(&layer) {
+                Ok(self.cutoff + std::time::Duration::from_secs(1))
+            } else {
+                Ok(self.cutoff)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn store_is_converted_between_memory_stores() {
+        let to = workdir("memory-store");
+        let work = format!("{to}/.workdir");
+        let from_store = MemoryLayerStore::new();
+        let to_store = MemoryLayerStore::new();
+        let from_labels = MemoryLabelStore::new();
+        let to_labels = MemoryLabelStore::new();
+
+        let mut builder = from_store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "moo\\tmoo"));
+        builder.commit_boxed().await.unwrap();
+        // written after the cutoff, so it is copied
+        let mut builder = from_store.create_child_layer(base).await.unwrap();
+        let child = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "a\\tb"));
+        builder.commit_boxed().await.unwrap();
+        let label = from_labels.create_label("animals").await.unwrap();
+        from_labels.set_label(&label, child).await.unwrap();
+
+        let cutoff = SystemTime::UNIX_EPOCH;
+        let creation_times = CopiedAfterCutoff {
+            cutoff,
+            copied: HashSet::from([child]),
+        };
+        let options = ConversionOptions::new(&work, cutoff)
+            .creation_times(Arc::new(creation_times))
+            .observer(Arc::new(PrintObserver::default()));
+        let from = format!("{to}-from");
+        convert_store_with_stores(
+            from_store,
+            to_store.clone(),
+            &from_labels,
+            &to_labels,
+            &from,
+            &to,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let label = to_labels.get_label("animals").await.unwrap().unwrap();
+        assert_eq!(Some(child), label.layer);
+        let converted = to_store.get_layer(child).await.unwrap().unwrap();
+        assert!(converted.value_triple_exists(&ValueTriple::new_string_value("cow", "says", "moo\tmoo")));
+        assert!(converted.value_triple_exists(&ValueTriple::new_string_value("duck", "says", "a\\tb")));
+        let journal = read_status_journal(&work).await.unwrap();
+        assert_eq!(Some(&ConversionStatus::Completed), journal.statuses.get(&base));
+        assert_eq!(Some(&ConversionStatus::Completed), journal.statuses.get(&child));
+        std::fs::remove_dir_all(to).unwrap();
+    }
+
+    /// Requests an interruption when the creation time of `layer` is
+    /// asked for, which is right before it is converted. If it requests
+    /// more than once, the layer never gets converted.
+    struct InterruptingCreationTimes {
+        interrupt: Interrupt,
+        layer: [u32; 5],
+        requests: usize,
+    }
+
+    #[async_trait]
+    impl LayerCreationTimes for InterruptingCreationTimes {
+        async fn creation_time(&self, layer: [u32; 5]) -> io::Result<SystemTime> {
+            if layer == self.layer {
+                for _ in 0..self.requests {
+                    self.interrupt.request();
+                }
+                if self.requests > 1 {
+                    std::future::pending::<()>().await;
+                }
+            }
+            Ok(SystemTime::UNIX_EPOCH)
+        }
+    }
+
+    #[derive(Default)]
+    struct CleanupRecorder {
+        cleaned_up: std::sync::Mutex<Vec<[u32; 5]>>,
+    }
+
+    impl ConversionObserver for CleanupRecorder {
+        fn removed(&self, removal: Removal<'_>) {
+            if let Removal::Layer(layer) = removal {
+                self.cleaned_up.lock().unwrap().push(layer);
+            }
+        }
+    }
+
+    /// Convert a base layer and its child, interrupting `requests` times
+    /// right before the base layer is converted.
+    async fn convert_interrupted(
+        name: &str,
+        requests: usize,
+    ) -> ([u32; 5], [u32; 5], Vec<[u32; 5]>, StatusJournal) {
+        let to = workdir(name);
+        let work = format!("{to}/.workdir");
+        let from_store = MemoryLayerStore::new();
+        let from_labels = MemoryLabelStore::new();
+        let mut builder = from_store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "moo"));
+        builder.commit_boxed().await.unwrap();
+        let mut builder = from_store.create_child_layer(base).await.unwrap();
+        let child = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "quack"));
+        builder.commit_boxed().await.unwrap();
+        let label = from_labels.create_label("main").await.unwrap();
+        from_labels.set_label(&label, child).await.unwrap();
+
+        let interrupt = Interrupt::new();
+        let creation_times = InterruptingCreationTimes {
+            interrupt: interrupt.clone(),
+            layer: base,
+            requests,
+        };
+        let observer = Arc::new(CleanupRecorder::default());
+        let options = ConversionOptions::new(&work, SystemTime::UNIX_EPOCH)
+            .interrupt(interrupt)
+            .creation_times(Arc::new(creation_times))
+            .observer(observer.clone());
+        let result = convert_store_with_stores(
+            from_store,
+            MemoryLayerStore::new(),
+            &from_labels,
+            &MemoryLabelStore::new(),
+            &format!("{to}-from"),
+            &to,
+            &options,
+        )
+        .await;
+        assert!(matches!(result, Err(StoreConversionError::Interrupted)));
+
+        let journal = read_status_journal(&work).await.unwrap();
+        std::fs::remove_dir_all(to).unwrap();
+        let cleaned_up = observer.cleaned_up.lock().unwrap().clone();
+        (base, child, cleaned_up, journal)
+    }
+
+    #[tokio::test]
+    async fn first_interruption_finishes_the_layers_in_progress() {
+        let (base, child, cleaned_up, journal) = convert_interrupted("interrupt-once", 1).await;
+
+        assert_eq!(Some(&ConversionStatus::Completed), journal.statuses.get(&base));
+        assert_eq!(None, journal.statuses.get(&child));
+        assert!(cleaned_up.is_empty());
+    }
+
+    #[tokio::test]
+    async fn second_interruption_aborts_the_layers_in_progress() {
+        let (base, child, cleaned_up, journal) = convert_interrupted("interrupt-twice", 2).await;
+
+        assert_eq!(Some(&ConversionStatus::Interrupted), journal.statuses.get(&base));
+        assert_eq!(None, journal.statuses.get(&child));
+        assert_eq!(vec![base], cleaned_up);
+    }
+
+    #[test]
+    fn parent_maps_are_unread_once_all_readers_complete() {
+        const LAYER3: [u32; 5] = [3, 3, 3, 3, 3];
+        const ROLLUP: [u32; 5] = [4, 4, 4, 4, 4];
+        // LAYER2 and LAYER3 are children of LAYER1, ROLLUP rolls up
+        // LAYER3 onto LAYER1.
+        let mut readers = ParentMapReaders::from_reads(HashMap::from([
+            (LAYER2, vec![LAYER1]),
+            (LAYER3, vec![LAYER1]),
+            (ROLLUP, vec![LAYER1, LAYER3]),
+        ]));
+
+        assert!(readers.is_read(LAYER1));
+        assert_eq!(Vec::<[u32; 5]>::new(), readers.completed(LAYER3));
+        assert_eq!(vec![LAYER2], readers.completed(LAYER2));
+        assert_eq!(vec![LAYER1, LAYER3, ROLLUP], readers.completed(ROLLUP));
+        assert!(!readers.is_read(LAYER1));
+    }
+}
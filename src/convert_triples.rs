@@ -1,24 +1,33 @@
-use std::collections::HashMap;
-
-use bytes::{Bytes, BytesMut};
 use futures::stream::TryStreamExt;
-use terminus_store::{storage::FileLoad, structure::{logarray_file_get_length_and_width, bitarray_stream_bits, logarray_stream_entries, LogArrayBufBuilder}};
+use terminus_store::{storage::{FileLoad, SyncableFile}, structure::{logarray_file_get_length_and_width, bitarray_stream_bits, logarray_stream_entries, BitArrayFileBuilder, LogArrayFileBuilder}};
+
+use crate::id_mapping::IdMapping;
 
 use std::io;
 
-pub async fn convert_sp_o_nums<F: FileLoad + 'static>(
+/// Remap the objects in an sp_o adjacency list.
+///
+/// Objects that collapse into the same id are deduplicated, so the
+/// bits of the adjacency list are written anew to `bits_output`. The
+/// new nums are written to `nums_output` as they are remapped, so only
+/// one group of objects is kept in memory at a time. Returns the
+/// number of triples that were removed this way.
+pub async fn convert_sp_o_nums<F: FileLoad + 'static, W: SyncableFile>(
     bits: F,
     nums: F,
-    mapping: &HashMap<u64, u64>,
-) -> io::Result<Bytes> {
+    mapping: &IdMapping,
+    bits_output: W,
+    nums_output: W,
+) -> io::Result<u64> {
     let (_len, width) = logarray_file_get_length_and_width(nums.clone()).await?;
     let mut bits_stream = bitarray_stream_bits(bits).await?;
     let mut nums_stream = logarray_stream_entries(nums).await?;
 
-    let mut buf = BytesMut::new();
-    let mut builder = LogArrayBufBuilder::new(&mut buf, width);
+    let mut builder = LogArrayFileBuilder::new(nums_output, width);
+    let mut bits_builder = BitArrayFileBuilder::new(bits_output);
 
     let mut tally = 0;
+    let mut removed = 0;
     while let Some(b) = bits_stream.try_next().await? {
         tally += 1;
         if b {
@@ -26,17 +35,41 @@ pub async fn convert_sp_o_nums<F: FileLoad + 'static>(
             let mut v = Vec::with_capacity(tally);
             for _ in 0..tally {
                 let unmapped = nums_stream.try_next().await?.unwrap();
-                let mapped = mapping.get(&unmapped).cloned().unwrap_or(unmapped);
-                v.push(mapped);
+                v.push(mapping.get(unmapped));
             }
             v.sort();
+            v.dedup();
+            removed += (tally - v.len()) as u64;
 
-            builder.push_vec(v);
+            for _ in 1..v.len() {
+                bits_builder.push(false).await?;
+            }
+            bits_builder.push(true).await?;
+            builder.push_vec(v).await?;
             tally = 0;
         }
     }
 
-    builder.finalize();
+    builder.finalize().await?;
+    bits_builder.finalize().await?;
+
+    Ok(removed)
+}
+
+/// Remap a subjects logarray into `output`. Subjects are always nodes,
+/// which never collapse, so the order and length stay the same.
+pub async fn convert_subjects<F: FileLoad + 'static, W: SyncableFile>(
+    subjects: F,
+    mapping: &IdMapping,
+    output: W,
+) -> io::Result<()> {
+    let (_len, width) = logarray_file_get_length_and_width(subjects.clone()).await?;
+    let mut subjects_stream = logarray_stream_entries(subjects).await?;
+
+    let mut builder = LogArrayFileBuilder::new(output, width);
+    while let Some(subject) = subjects_stream.try_next().await? {
+        builder.push(mapping.get(subject)).await?;
+    }
 
-    Ok(buf.freeze())
+    builder.finalize().await
 }
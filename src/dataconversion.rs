@@ -1,65 +1,480 @@
 use std::borrow::Cow;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use clap::ValueEnum;
+use thiserror::Error;
 
 const SWIPL_CONTROL_CHAR_A: char = 7 as char;
 const SWIPL_CONTROL_CHAR_B: char = 8 as char;
+const SWIPL_CONTROL_CHAR_E: char = 27 as char;
 const SWIPL_CONTROL_CHAR_F: char = 12 as char;
 const SWIPL_CONTROL_CHAR_V: char = 11 as char;
 
-pub fn prolog_string_to_string(s: &str) -> Cow<str> {
+/// What to do with an escape sequence that cannot be decoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MalformedEscapePolicy {
+    /// Fail the conversion
+    #[default]
+    Strict,
+    /// Keep the raw escape sequence text, including the backslash
+    Preserve,
+    /// Substitute the unicode replacement character (U+FFFD)
+    Replace,
+    /// Leave the escape sequence out
+    Drop,
+}
+
+impl fmt::Display for MalformedEscapePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Strict => "strict",
+            Self::Preserve => "preserve",
+            Self::Replace => "replace",
+            Self::Drop => "drop",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How many bytes of context to include on either side of a bad
+/// escape sequence when reporting it.
+const SNIPPET_CONTEXT: usize = 16;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EscapeDecodeErrorKind {
+    #[error("unknown escape code `{0}`")]
+    UnknownEscapeCode(char),
+    #[error("unterminated escape sequence")]
+    Unterminated,
+    #[error("invalid digits in escape sequence")]
+    InvalidDigits,
+    #[error("escape sequence denotes invalid code point {0:#x}")]
+    InvalidCodePoint(u32),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("{kind} at byte {offset}: `{sequence}` in `{snippet}`")]
+pub struct EscapeDecodeError {
+    pub offset: usize,
+    pub sequence: String,
+    pub snippet: String,
+    pub kind: EscapeDecodeErrorKind,
+}
+
+impl EscapeDecodeError {
+    fn new(s: &str, start: usize, end: usize, kind: EscapeDecodeErrorKind) -> Self {
+        let snippet_start = floor_char_boundary(s, start.saturating_sub(SNIPPET_CONTEXT));
+        let snippet_end = ceil_char_boundary(s, end + SNIPPET_CONTEXT);
+        Self {
+            offset: start,
+            sequence: s[start..end].to_string(),
+            snippet: s[snippet_start..snippet_end].to_string(),
+            kind,
+        }
+    }
+}
+
+fn floor_char_boundary(s: &str, mut ix: usize) -> usize {
+    while !s.is_char_boundary(ix) {
+        ix -= 1;
+    }
+    ix
+}
+
+fn ceil_char_boundary(s: &str, mut ix: usize) -> usize {
+    if ix >= s.len() {
+        return s.len();
+    }
+    while !s.is_char_boundary(ix) {
+        ix += 1;
+    }
+    ix
+}
+
+/// Decode the escape sequences that SWI-Prolog writes in quoted strings.
+///
+/// Supported are the single character escapes (`\\ \" \' \` \a \b \e
+/// \f \n \r \s \t \v`), hexadecimal (`\xXX..\`) and octal (`\NNN\`)
+/// character codes, `\uXXXX` and `\UXXXXXXXX`, and a backslash
+/// followed by a newline, which is a line continuation.
+pub fn prolog_string_to_string(s: &str) -> Result<Cow<'_, str>, EscapeDecodeError> {
+    prolog_string_to_string_with_policy(s, MalformedEscapePolicy::Strict, &mut Vec::new())
+}
+
+/// Like [`prolog_string_to_string`], but malformed escape sequences
+/// are handled according to `policy`. Unless the policy is strict,
+/// every malformed sequence is pushed onto `fallbacks` instead of
+/// failing the decode.
+pub fn prolog_string_to_string_with_policy<'a>(
+    s: &'a str,
+    policy: MalformedEscapePolicy,
+    fallbacks: &mut Vec<EscapeDecodeError>,
+) -> Result<Cow<'a, str>, EscapeDecodeError> {
     let mut result: Option<String> = None;
-    let mut escaping = false;
-    let mut characters = s.char_indices();
+    let mut characters = s.char_indices().peekable();
     while let Some((ix, c)) = characters.next() {
-        if escaping {
-            let result = result.as_mut().unwrap();
-            match c {
-                '\\' => result.push('\\'),
-                '\"' => result.push('\"'),
-                'x' => result.push(unescape_legacy_prolog_escape_sequence(&mut characters)),
-                'a' => result.push(SWIPL_CONTROL_CHAR_A),
-                'b' => result.push(SWIPL_CONTROL_CHAR_B),
-                't' => result.push('\t'),
-                'n' => result.push('\n'),
-                'v' => result.push(SWIPL_CONTROL_CHAR_V),
-                'f' => result.push(SWIPL_CONTROL_CHAR_F),
-                'r' => result.push('\r'),
-                _ => panic!("unknown prolog escape code in string"),
-            }
-
-            escaping = false;
-        } else {
-            if c == '\\' {
-                escaping = true;
-                if result.is_none() {
-                    let mut r = String::with_capacity(s.len());
-                    r.push_str(&s[..ix]);
-                    result = Some(r);
+        if c == '\\' {
+            let result = result.get_or_insert_with(|| {
+                let mut r = String::with_capacity(s.len());
+                r.push_str(&s[..ix]);
+                r
+            });
+            match decode_escape_sequence(&mut characters) {
+                Ok(Some(decoded)) => result.push(decoded),
+                Ok(None) => {}
+                Err(kind) => {
+                    let end = characters.peek().map(|(ix, _)| *ix).unwrap_or(s.len());
+                    let error = EscapeDecodeError::new(s, ix, end, kind);
+                    match policy {
+                        MalformedEscapePolicy::Strict => return Err(error),
+                        MalformedEscapePolicy::Preserve => result.push_str(&s[ix..end]),
+                        MalformedEscapePolicy::Replace => result.push(char::REPLACEMENT_CHARACTER),
+                        MalformedEscapePolicy::Drop => {}
+                    }
+                    fallbacks.push(error);
                 }
-            } else if let Some(result) = result.as_mut() {
-                result.push(c);
             }
+        } else if let Some(result) = result.as_mut() {
+            result.push(c);
         }
     }
 
     match result {
-        Some(result) => {
-            Cow::Owned(result)
-        }
-        None => Cow::Borrowed(s),
+        Some(result) => Ok(Cow::Owned(result)),
+        None => Ok(Cow::Borrowed(s)),
     }
 }
 
+/// Decode a single escape sequence, with the leading backslash
+/// already consumed. Returns `None` for a line continuation, which
+/// decodes to nothing.
+fn decode_escape_sequence(
+    characters: &mut Peekable<CharIndices>,
+) -> Result<Option<char>, EscapeDecodeErrorKind> {
+    let (_, code) = characters
+        .next()
+        .ok_or(EscapeDecodeErrorKind::Unterminated)?;
+    let decoded = match code {
+        '\\' => '\\',
+        '\"' => '\"',
+        '\'' => '\'',
+        '`' => '`',
+        'x' => unescape_legacy_prolog_escape_sequence(characters, String::new(), 16)?,
+        '0'..='7' => unescape_legacy_prolog_escape_sequence(characters, code.to_string(), 8)?,
+        'u' => unescape_fixed_width_sequence(characters, 4)?,
+        'U' => unescape_fixed_width_sequence(characters, 8)?,
+        'a' => SWIPL_CONTROL_CHAR_A,
+        'b' => SWIPL_CONTROL_CHAR_B,
+        'e' => SWIPL_CONTROL_CHAR_E,
+        's' => ' ',
+        't' => '\t',
+        'n' => '\n',
+        'v' => SWIPL_CONTROL_CHAR_V,
+        'f' => SWIPL_CONTROL_CHAR_F,
+        'r' => '\r',
+        // line continuation, the newline is skipped
+        '\n' => return Ok(None),
+        _ => return Err(EscapeDecodeErrorKind::UnknownEscapeCode(code)),
+    };
+
+    Ok(Some(decoded))
+}
+
+/// Read the digits of a `\x..\` or `\NNN\` escape. The closing
+/// backslash is optional, as SWI-Prolog accepts it either way.
 fn unescape_legacy_prolog_escape_sequence(
-    characters: &mut impl Iterator<Item = (usize, char)>,
-) -> char {
-    let mut digits: String = String::new();
-    loop {
-        let (_, digit) = characters.next().unwrap();
-        if digit == '\\' {
-            let hex = u32::from_str_radix(&digits, 16).unwrap();
-            return char::from_u32(hex).unwrap();
-        } else {
-            digits.push(digit);
+    characters: &mut Peekable<CharIndices>,
+    mut digits: String,
+    radix: u32,
+) -> Result<char, EscapeDecodeErrorKind> {
+    while let Some((_, digit)) = characters.next_if(|(_, c)| c.is_digit(radix)) {
+        digits.push(digit);
+    }
+    if digits.is_empty() {
+        return Err(match characters.peek() {
+            Some(_) => EscapeDecodeErrorKind::InvalidDigits,
+            None => EscapeDecodeErrorKind::Unterminated,
+        });
+    }
+    characters.next_if(|(_, c)| *c == '\\');
+
+    code_to_char(&digits, radix)
+}
+
+/// Read exactly `width` hexadecimal digits of a `\u` or `\U` escape.
+fn unescape_fixed_width_sequence(
+    characters: &mut Peekable<CharIndices>,
+    width: usize,
+) -> Result<char, EscapeDecodeErrorKind> {
+    let mut digits: String = String::with_capacity(width);
+    for _ in 0..width {
+        let (_, digit) = characters
+            .next_if(|(_, c)| c.is_ascii_hexdigit())
+            .ok_or_else(|| match characters.peek() {
+                Some(_) => EscapeDecodeErrorKind::InvalidDigits,
+                None => EscapeDecodeErrorKind::Unterminated,
+            })?;
+        digits.push(digit);
+    }
+
+    code_to_char(&digits, 16)
+}
+
+fn code_to_char(digits: &str, radix: u32) -> Result<char, EscapeDecodeErrorKind> {
+    // digits have been validated, so this can only fail on overflow
+    let code = u32::from_str_radix(digits, radix)
+        .map_err(|_| EscapeDecodeErrorKind::InvalidDigits)?;
+    char::from_u32(code).ok_or(EscapeDecodeErrorKind::InvalidCodePoint(code))
+}
+
+/// Encode a string the way SWI-Prolog quotes it, which is the form
+/// TerminusDB 10 stored. This is the inverse of
+/// [`prolog_string_to_string`].
+///
+/// Only backslashes, double quotes and control characters are
+/// escaped. Control characters without a symbolic escape are written
+/// as octal `\NNN\` sequences, like SWI-Prolog does.
+pub fn string_to_prolog_string(s: &str) -> Cow<'_, str> {
+    let first_escape = s.find(needs_escape);
+    if first_escape.is_none() {
+        return Cow::Borrowed(s);
+    }
+    let first_escape = first_escape.unwrap();
+
+    let mut result = String::with_capacity(s.len() + 8);
+    result.push_str(&s[..first_escape]);
+    for c in s[first_escape..].chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\"' => result.push_str("\\\""),
+            SWIPL_CONTROL_CHAR_A => result.push_str("\\a"),
+            SWIPL_CONTROL_CHAR_B => result.push_str("\\b"),
+            SWIPL_CONTROL_CHAR_E => result.push_str("\\e"),
+            '\t' => result.push_str("\\t"),
+            '\n' => result.push_str("\\n"),
+            SWIPL_CONTROL_CHAR_V => result.push_str("\\v"),
+            SWIPL_CONTROL_CHAR_F => result.push_str("\\f"),
+            '\r' => result.push_str("\\r"),
+            c if c.is_control() => result.push_str(&format!("\\{:03o}\\", c as u32)),
+            c => result.push(c),
         }
     }
+
+    Cow::Owned(result)
+}
+
+fn needs_escape(c: char) -> bool {
+    c == '\\' || c == '\"' || c.is_control()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(s: &str) -> String {
+        prolog_string_to_string(s).unwrap().into_owned()
+    }
+
+    #[test]
+    fn plain_strings_are_borrowed() {
+        assert!(matches!(
+            prolog_string_to_string("hello"),
+            Ok(Cow::Borrowed("hello"))
+        ));
+    }
+
+    #[test]
+    fn single_character_escapes() {
+        assert_eq!("a\\b", decode("a\\\\b"));
+        assert_eq!("a\"b", decode("a\\\"b"));
+        assert_eq!("a'b", decode("a\\'b"));
+        assert_eq!("a`b", decode("a\\`b"));
+        assert_eq!(
+            "\x07\x08\x1b\x0c\n\r \t\x0b",
+            decode("\\a\\b\\e\\f\\n\\r\\s\\t\\v")
+        );
+    }
+
+    #[test]
+    fn octal_escape() {
+        assert_eq!("a\x1bb", decode("a\\033\\b"));
+        assert_eq!("A", decode("\\101\\"));
+        // the closing backslash is optional
+        assert_eq!("A", decode("\\101"));
+    }
+
+    #[test]
+    fn hex_escape() {
+        assert_eq!("AB", decode("\\x41\\B"));
+        assert_eq!("é", decode("\\xe9\\"));
+        assert_eq!("\u{1F600}", decode("\\x1F600\\"));
+    }
+
+    #[test]
+    fn short_unicode_escape() {
+        assert_eq!("é1", decode("\\u00e91"));
+    }
+
+    #[test]
+    fn long_unicode_escape() {
+        assert_eq!("\u{1F600}1", decode("\\U0001F6001"));
+    }
+
+    #[test]
+    fn line_continuation() {
+        assert_eq!("ab", decode("a\\\nb"));
+    }
+
+    fn decode_error(s: &str) -> EscapeDecodeError {
+        prolog_string_to_string(s).unwrap_err()
+    }
+
+    #[test]
+    fn lone_surrogate_is_an_invalid_code_point() {
+        let error = decode_error("ab\\uD800cd");
+        assert_eq!(EscapeDecodeErrorKind::InvalidCodePoint(0xd800), error.kind);
+        assert_eq!(2, error.offset);
+        assert_eq!("\\uD800", error.sequence);
+        assert_eq!("ab\\uD800cd", error.snippet);
+    }
+
+    #[test]
+    fn unterminated_hex_escape() {
+        let error = decode_error("ab\\x");
+        assert_eq!(EscapeDecodeErrorKind::Unterminated, error.kind);
+        assert_eq!(2, error.offset);
+        assert_eq!("\\x", error.sequence);
+        // with digits, the closing backslash may be left out
+        assert_eq!("abA", decode("ab\\x41"));
+    }
+
+    #[test]
+    fn bad_hex_digits() {
+        let error = decode_error("a\\xzz\\");
+        assert_eq!(EscapeDecodeErrorKind::InvalidDigits, error.kind);
+        assert_eq!(1, error.offset);
+        assert_eq!("\\x", error.sequence);
+
+        let error = decode_error("a\\u12g4");
+        assert_eq!(EscapeDecodeErrorKind::InvalidDigits, error.kind);
+        assert_eq!("\\u12", error.sequence);
+    }
+
+    #[test]
+    fn unknown_escape_letter() {
+        let error = decode_error("a\\qb");
+        assert_eq!(EscapeDecodeErrorKind::UnknownEscapeCode('q'), error.kind);
+        assert_eq!(1, error.offset);
+        assert_eq!("\\q", error.sequence);
+        assert_eq!("a\\qb", error.snippet);
+    }
+
+    #[test]
+    fn trailing_backslash() {
+        let error = decode_error("abc\\");
+        assert_eq!(EscapeDecodeErrorKind::Unterminated, error.kind);
+        assert_eq!(3, error.offset);
+        assert_eq!("\\", error.sequence);
+    }
+
+    #[test]
+    fn snippet_is_cut_at_char_boundaries() {
+        let s = format!("{}\\q{}", "é".repeat(20), "ü".repeat(20));
+        let error = decode_error(&s);
+        assert_eq!(40, error.offset);
+        assert_eq!(
+            format!("{}\\q{}", "é".repeat(8), "ü".repeat(8)),
+            error.snippet
+        );
+    }
+
+    fn decode_with(
+        policy: MalformedEscapePolicy,
+    ) -> (Result<String, EscapeDecodeError>, Vec<EscapeDecodeError>) {
+        let mut fallbacks = Vec::new();
+        let result = prolog_string_to_string_with_policy("a\\qb\\n\\uD800", policy, &mut fallbacks)
+            .map(Cow::into_owned);
+        (result, fallbacks)
+    }
+
+    #[test]
+    fn strict_policy_fails_on_the_first_malformed_escape() {
+        let (result, fallbacks) = decode_with(MalformedEscapePolicy::Strict);
+        assert_eq!(
+            EscapeDecodeErrorKind::UnknownEscapeCode('q'),
+            result.unwrap_err().kind
+        );
+        assert!(fallbacks.is_empty());
+    }
+
+    #[test]
+    fn preserve_policy_keeps_the_raw_sequences() {
+        let (result, fallbacks) = decode_with(MalformedEscapePolicy::Preserve);
+        assert_eq!("a\\qb\n\\uD800", result.unwrap());
+        assert_fallbacks(&fallbacks);
+    }
+
+    #[test]
+    fn replace_policy_substitutes_the_replacement_character() {
+        let (result, fallbacks) = decode_with(MalformedEscapePolicy::Replace);
+        assert_eq!("a\u{fffd}b\n\u{fffd}", result.unwrap());
+        assert_fallbacks(&fallbacks);
+    }
+
+    #[test]
+    fn drop_policy_leaves_the_sequences_out() {
+        let (result, fallbacks) = decode_with(MalformedEscapePolicy::Drop);
+        assert_eq!("ab\n", result.unwrap());
+        assert_fallbacks(&fallbacks);
+    }
+
+    fn assert_fallbacks(fallbacks: &[EscapeDecodeError]) {
+        let fallbacks: Vec<_> = fallbacks
+            .iter()
+            .map(|error| (error.offset, error.sequence.as_str(), &error.kind))
+            .collect();
+        assert_eq!(
+            vec![
+                (1, "\\q", &EscapeDecodeErrorKind::UnknownEscapeCode('q')),
+                (
+                    6,
+                    "\\uD800",
+                    &EscapeDecodeErrorKind::InvalidCodePoint(0xd800)
+                ),
+            ],
+            fallbacks
+        );
+    }
+
+    fn assert_round_trips(s: &str) {
+        let encoded = string_to_prolog_string(s);
+        assert_eq!(s, prolog_string_to_string(&encoded).unwrap());
+    }
+
+    #[test]
+    fn control_characters_are_escaped_like_swipl() {
+        assert_eq!(
+            "\\a\\b\\e\\f\\n\\r\\t\\v\\001\\\\177\\",
+            string_to_prolog_string("\x07\x08\x1b\x0c\n\r\t\x0b\x01\x7f")
+        );
+        assert_round_trips("\x07\x08\x1b\x0c\n\r\t\x0b\x01\x7f\u{85}");
+    }
+
+    #[test]
+    fn quotes_and_backslashes_round_trip() {
+        assert_eq!(
+            "\\\"it's\\\" \\\\ `x`",
+            string_to_prolog_string("\"it's\" \\ `x`")
+        );
+        assert_round_trips("\"it's\" \\ `x` \\\\n");
+    }
+
+    #[test]
+    fn non_ascii_text_is_left_alone() {
+        let s = "héllo wörld \u{1F600} 日本語";
+        assert!(matches!(string_to_prolog_string(s), Cow::Borrowed(_)));
+        assert_round_trips(s);
+    }
 }
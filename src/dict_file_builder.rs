@@ -0,0 +1,227 @@
+use bytes::BytesMut;
+use terminus_store::storage::SyncableFile;
+use terminus_store::structure::tfc::block::BLOCK_SIZE;
+use terminus_store::structure::tfc::dict::SizedDictBufBuilder;
+use terminus_store::structure::util::calculate_width;
+use terminus_store::structure::{
+    Datatype, LateLogArrayBufBuilder, LogArray, LogArrayFileBuilder, TypedDictEntry,
+};
+use tokio::io::AsyncWriteExt;
+
+use crate::external_sort::ScratchDir;
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+/// How many bytes of blocks are kept in memory before they are written
+/// to the blocks file.
+const FLUSH_SIZE: u64 = 1 << 20;
+
+/// Builds a typed dictionary like `TypedDictBufBuilder`, but writes its
+/// blocks to the blocks file as it goes rather than keeping them in
+/// memory. The block offsets are spilled to the scratch directory, as
+/// the width of their logarray is only known once every block is
+/// written. Entries have to be added in order.
+pub struct TypedDictFileBuilder<W: SyncableFile> {
+    types_present_file: W,
+    type_offsets_file: W,
+    offsets_file: W,
+    blocks_file: W,
+    types_present: LateLogArrayBufBuilder<BytesMut>,
+    type_offsets: LateLogArrayBufBuilder<BytesMut>,
+    /// the builder for the blocks of the current datatype, which is
+    /// replaced whenever its blocks are written out.
+    segment: Option<(Datatype, SizedDictBufBuilder<BytesMut, BytesMut>)>,
+    /// entries added to the current builder, to tell where a block ends
+    segment_entries: usize,
+    block_offset: u64,
+    id_offset: u64,
+    block_offsets_path: PathBuf,
+    block_offsets: BufWriter<File>,
+    block_offset_count: u64,
+    last_block_offset: u64,
+}
+
+impl<W: SyncableFile> TypedDictFileBuilder<W> {
+    pub fn new(
+        scratch: &ScratchDir,
+        types_present_file: W,
+        type_offsets_file: W,
+        offsets_file: W,
+        blocks_file: W,
+    ) -> io::Result<Self> {
+        let (block_offsets_path, block_offsets) = scratch.create_file("block-offsets")?;
+
+        Ok(Self {
+            types_present_file,
+            type_offsets_file,
+            offsets_file,
+            blocks_file,
+            types_present: LateLogArrayBufBuilder::new(BytesMut::new()),
+            type_offsets: LateLogArrayBufBuilder::new(BytesMut::new()),
+            segment: None,
+            segment_entries: 0,
+            block_offset: 0,
+            id_offset: 0,
+            block_offsets_path,
+            block_offsets: BufWriter::new(block_offsets),
+            block_offset_count: 0,
+            last_block_offset: 0,
+        })
+    }
+
+    pub async fn add(&mut self, entry: TypedDictEntry) -> io::Result<()> {
+        let datatype = entry.datatype();
+        let current_datatype = self.segment.as_ref().map(|(datatype, _)| *datatype);
+        if current_datatype != Some(datatype) {
+            if current_datatype.is_some() {
+                self.write_segment().await?;
+                self.type_offsets.push(self.block_offset_count - 1);
+            }
+            self.types_present.push(datatype as u64);
+            self.start_segment(datatype);
+        }
+
+        let (_, segment) = self.segment.as_mut().unwrap();
+        segment.add(entry.to_bytes());
+        self.segment_entries += 1;
+        // the builder is only replaced between blocks, so that the
+        // blocks come out the same as when all entries go into one.
+        let unwritten = segment.block_offset() - self.block_offset;
+        if self.segment_entries.is_multiple_of(BLOCK_SIZE) && unwritten >= FLUSH_SIZE {
+            self.write_segment().await?;
+            self.start_segment(datatype);
+        }
+
+        Ok(())
+    }
+
+    fn start_segment(&mut self, datatype: Datatype) {
+        let segment = SizedDictBufBuilder::new(
+            datatype.record_size(),
+            self.block_offset,
+            self.id_offset,
+            LateLogArrayBufBuilder::new(BytesMut::new()),
+            BytesMut::new(),
+        );
+        self.segment = Some((datatype, segment));
+        self.segment_entries = 0;
+    }
+
+    /// Write the blocks of the current builder to the blocks file, and
+    /// their offsets to the scratch directory.
+    async fn write_segment(&mut self) -> io::Result<()> {
+        let (_, segment) = match self.segment.take() {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
+        let (offsets, data, block_offset, id_offset) = segment.finalize();
+        self.blocks_file.write_all(&data).await?;
+        for offset in LogArray::parse(offsets.finalize().freeze())?.iter() {
+            self.block_offsets.write_all(&offset.to_be_bytes())?;
+            self.block_offset_count += 1;
+            self.last_block_offset = offset;
+        }
+        self.block_offset = block_offset;
+        self.id_offset = id_offset;
+
+        Ok(())
+    }
+
+    pub async fn finalize(mut self) -> io::Result<()> {
+        self.write_segment().await?;
+
+        self.block_offsets.flush()?;
+        // as with TypedDictBufBuilder, the last block offset is left
+        // out, as it follows from the size of the blocks.
+        let width = if self.block_offset_count == 0 {
+            0
+        } else {
+            calculate_width(self.last_block_offset)
+        };
+        let mut offsets = LogArrayFileBuilder::new(self.offsets_file, width);
+        let mut block_offsets = BufReader::new(File::open(&self.block_offsets_path)?);
+        for _ in 1..self.block_offset_count {
+            let mut offset = [0; 8];
+            block_offsets.read_exact(&mut offset)?;
+            offsets.push(u64::from_be_bytes(offset)).await?;
+        }
+        offsets.finalize().await?;
+
+        write_and_sync(self.blocks_file, &self.id_offset.to_be_bytes()).await?;
+        write_and_sync(self.types_present_file, &self.types_present.finalize()).await?;
+        write_and_sync(self.type_offsets_file, &self.type_offsets.finalize()).await
+    }
+}
+
+async fn write_and_sync<W: SyncableFile>(mut file: W, bytes: &[u8]) -> io::Result<()> {
+    file.write_all(bytes).await?;
+    file.flush().await?;
+    file.sync_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::storage::consts::FILENAMES;
+    use terminus_store::storage::memory::MemoryLayerStore;
+    use terminus_store::storage::{FileLoad, FileStore, PersistentLayerStore};
+    use terminus_store::structure::{TdbDataType, TypedDictBufBuilder};
+
+    #[tokio::test]
+    async fn writes_the_same_dictionary_as_the_buf_builder() {
+        // enough entries for the blocks to be written out a few times
+        let mut entries: Vec<TypedDictEntry> = (0..100_000)
+            .map(|i| String::make_entry(&format!("{i:08} is a fairly long string value")))
+            .chain((0..1000_u32).map(|i| u32::make_entry(&i)))
+            .chain((0..1000_i64).map(|i| i64::make_entry(&i)))
+            .collect();
+        entries.sort();
+
+        let mut expected = (BytesMut::new(), BytesMut::new(), BytesMut::new(), BytesMut::new());
+        let mut buf_builder = TypedDictBufBuilder::new(
+            &mut expected.0,
+            &mut expected.1,
+            &mut expected.2,
+            &mut expected.3,
+        );
+        buf_builder.add_all(entries.iter().cloned());
+        buf_builder.finalize();
+
+        let store = MemoryLayerStore::default();
+        let id = [1, 2, 3, 4, 5];
+        store.create_named_directory(id).await.unwrap();
+        let names = [
+            FILENAMES.value_dictionary_types_present,
+            FILENAMES.value_dictionary_type_offsets,
+            FILENAMES.value_dictionary_offsets,
+            FILENAMES.value_dictionary_blocks,
+        ];
+        let mut files = Vec::new();
+        for name in names {
+            files.push(store.get_file(id, name).await.unwrap());
+        }
+        let scratch = ScratchDir::new(std::env::temp_dir().join(format!(
+            "escape-fixup-dict-builder-{}",
+            std::process::id()
+        )));
+        let mut builder = TypedDictFileBuilder::new(
+            &scratch,
+            files[0].open_write().await.unwrap(),
+            files[1].open_write().await.unwrap(),
+            files[2].open_write().await.unwrap(),
+            files[3].open_write().await.unwrap(),
+        )
+        .unwrap();
+        for entry in entries {
+            builder.add(entry).await.unwrap();
+        }
+        builder.finalize().await.unwrap();
+
+        let expected = [expected.0, expected.1, expected.2, expected.3];
+        for (file, expected) in files.iter().zip(expected) {
+            assert_eq!(expected.freeze(), file.map().await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use terminus_store::storage::archive::ArchiveLayerStore;
+use terminus_store::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
+use terminus_store::storage::PersistentLayerStore;
+
+use crate::convert_dictionary::analyze_value_dict;
+use crate::convert_store::ConversionOptions;
+use crate::external_sort::DRY_RUN_DIR;
+use crate::layout::StoreLayout;
+use crate::observer::DryRunOutcome;
+use crate::reachable::find_reachable_layers;
+
+use std::io;
+use std::path::PathBuf;
+
+use tokio::fs;
+
+/// What converting a store would do, as found by [`dry_run_store`].
+#[derive(Debug, Default)]
+pub struct DryRunSummary {
+    pub layers: u64,
+    /// Layers that would be copied rather than converted.
+    pub copied: u64,
+    /// Layers whose values would have to be reordered.
+    pub reordered_layers: u64,
+    pub changed: u64,
+    pub moved: u64,
+    pub duplicates: u64,
+    pub fallbacks: u64,
+    /// Layers that would fail to convert, with the reason.
+    pub failures: Vec<([u32; 5], String)>,
+}
+
+/// Decode the value dictionary of every reachable layer the way
+/// `convert_store` would with `options`, but without writing anything
+/// besides the sorted runs of values that take more than the memory
+/// limit, and report what would change to the observer of `options`.
+pub async fn dry_run_store(from: &str, options: &ConversionOptions) -> io::Result<DryRunSummary> {
+    match options.layout {
+        StoreLayout::Archive => {
+            let layer_store = ArchiveLayerStore::new(from);
+            dry_run_store_with_store(&layer_store, from, options).await
+        }
+        StoreLayout::Directory => {
+            let layer_store = DirectoryLayerStore::new(from);
+            dry_run_store_with_store(&layer_store, from, options).await
+        }
+    }
+}
+
+async fn dry_run_store_with_store<S: PersistentLayerStore>(
+    v10_layer_store: &S,
+    from: &str,
+    options: &ConversionOptions,
+) -> io::Result<DryRunSummary> {
+    let ConversionOptions {
+        work,
+        cutoff,
+        policy,
+        reverse,
+        memory_limit,
+        ..
+    } = options.clone();
+    let observer = options.observer_or_default();
+    let creation_times = options.creation_times_or_default(from);
+    let v10_label_store = DirectoryLabelStore::new(from);
+
+    let reachable = find_reachable_layers(v10_layer_store, &v10_label_store, &*observer).await?;
+    let mut layers: Vec<[u32; 5]> = reachable.values().flatten().cloned().collect();
+    layers.sort();
+    layers.dedup();
+
+    let mut summary = DryRunSummary::default();
+    for layer in layers {
+        summary.layers += 1;
+        if !reverse && creation_times.creation_time(layer).await? > cutoff {
+            observer.dry_run_layer(layer, DryRunOutcome::Copied);
+            summary.copied += 1;
+            continue;
+        }
+
+        match analyze_value_dict(v10_layer_store, &work, memory_limit, layer, policy, reverse).await {
+            Ok(analysis) => {
+                observer.dry_run_layer(layer, DryRunOutcome::Converted(&analysis));
+                if analysis.moved != 0 || analysis.duplicates != 0 {
+                    summary.reordered_layers += 1;
+                }
+                summary.changed += analysis.changed;
+                summary.moved += analysis.moved;
+                summary.duplicates += analysis.duplicates;
+                summary.fallbacks += analysis.fallbacks;
+            }
+            Err(e) => {
+                observer.dry_run_layer(layer, DryRunOutcome::Failed(&e));
+                summary.failures.push((layer, e.to_string()));
+            }
+        }
+    }
+
+    // the scratch directory of every layer is removed once it is
+    // analyzed, which leaves the one they were in empty.
+    let _ = fs::remove_dir(PathBuf::from(&work).join(DRY_RUN_DIR)).await;
+    observer.dry_run_finished(&summary);
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert_store::LayerCreationTimes;
+    use crate::observer::ConversionObserver;
+    use async_trait::async_trait;
+    use terminus_store::layer::ValueTriple;
+    use terminus_store::storage::memory::MemoryLayerStore;
+    use terminus_store::storage::{LabelStore, LayerStore};
+
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    /// Says every layer was written at the cutoff, so none is copied.
+    struct AtCutoff;
+
+    #[async_trait]
+    impl LayerCreationTimes for AtCutoff {
+        async fn creation_time(&self, _layer: [u32; 5]) -> io::Result<SystemTime> {
+            Ok(SystemTime::UNIX_EPOCH)
+        }
+    }
+
+    #[derive(Default)]
+    struct DryRunRecorder {
+        changed: Mutex<Vec<([u32; 5], u64)>>,
+        total_changed: Mutex<Option<u64>>,
+    }
+
+    impl ConversionObserver for DryRunRecorder {
+        fn dry_run_layer(&self, layer: [u32; 5], outcome: DryRunOutcome<'_>) {
+            if let DryRunOutcome::Converted(analysis) = outcome {
+                self.changed.lock().unwrap().push((layer, analysis.changed));
+            }
+        }
+
+        fn dry_run_finished(&self, summary: &DryRunSummary) {
+            *self.total_changed.lock().unwrap() = Some(summary.changed);
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_is_reported_to_the_observer() {
+        let from = std::env::temp_dir().join(format!("escape-fixup-dry-run-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&from);
+        std::fs::create_dir_all(&from).unwrap();
+        let from = from.to_str().unwrap();
+        let store = MemoryLayerStore::new();
+        let mut builder = store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "moo\\tmoo"));
+        builder.add_value_triple(ValueTriple::new_string_value("duck", "says", "quack"));
+        builder.commit_boxed().await.unwrap();
+        let labels = DirectoryLabelStore::new(from);
+        let label = labels.create_label("animals").await.unwrap();
+        labels.set_label(&label, base).await.unwrap();
+
+        let recorder = Arc::new(DryRunRecorder::default());
+        let options = ConversionOptions::new(&format!("{from}/.workdir"), SystemTime::UNIX_EPOCH)
+            .creation_times(Arc::new(AtCutoff))
+            .observer(recorder.clone());
+        let summary = dry_run_store_with_store(&store, from, &options).await.unwrap();
+        std::fs::remove_dir_all(from).unwrap();
+
+        assert_eq!(vec![(base, 1)], *recorder.changed.lock().unwrap());
+        assert_eq!(Some(1), *recorder.total_changed.lock().unwrap());
+        assert_eq!(1, summary.changed);
+    }
+}
@@ -0,0 +1,475 @@
+use bytes::{Buf, Bytes};
+use num_traits::FromPrimitive;
+use terminus_store::layer::IdTriple;
+use terminus_store::storage::name_to_string;
+use terminus_store::structure::{Datatype, SizedDictEntry, TypedDictEntry};
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::marker::PhantomData;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Memory taken by a buffered entry besides its bytes: the entry
+/// itself, its old index and the chunks it is made of.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// The least memory the buffer gets before it is spilled, even when
+/// the reserved memory leaves less than that, so that runs do not
+/// shrink to a single entry.
+const MIN_BUFFER: usize = 1 << 20;
+
+/// The most runs that are merged at once. Every open run holds a file
+/// and its read buffer, so more runs are merged in several passes.
+const MAX_FAN_IN: usize = 64;
+
+/// The directory in the workdir that holds the scratch directories of
+/// a dry run.
+pub const DRY_RUN_DIR: &str = "dry-run";
+
+/// A directory for the temporary files of a conversion step, created
+/// when the first file is written and removed with everything in it
+/// when dropped.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// The scratch directory of a layer, named after the layer so that
+    /// it is cleaned up with the rest of an unfinished layer.
+    pub fn for_layer(work: &str, id: [u32; 5]) -> Self {
+        let name = name_to_string(id);
+        let mut pathbuf = PathBuf::from(work);
+        pathbuf.push(&name[..3]);
+        pathbuf.push(format!("{name}.sort"));
+
+        Self::new(pathbuf)
+    }
+
+    /// The scratch directory of a dry run of a layer, apart from that
+    /// of a conversion of the layer that may be running meanwhile.
+    pub fn for_dry_run(work: &str, id: [u32; 5]) -> Self {
+        let mut pathbuf = PathBuf::from(work);
+        pathbuf.push(DRY_RUN_DIR);
+        pathbuf.push(format!("{}.sort", name_to_string(id)));
+
+        Self::new(pathbuf)
+    }
+
+    /// Create a file in the directory, creating the directory first if
+    /// it does not exist yet.
+    pub fn create_file(&self, name: &str) -> io::Result<(PathBuf, File)> {
+        fs::create_dir_all(&self.path)?;
+        let path = self.path.join(name);
+        let file = File::create(&path)?;
+
+        Ok((path, file))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        // anything left behind is removed by clean_unfinished_layers
+        // when the conversion resumes.
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A record that can be sorted by an [`ExternalSorter`], and written
+/// to and read back from a run.
+pub trait SortRecord: Ord + Sized {
+    /// The memory taken by a buffered record.
+    fn memory(&self) -> usize;
+
+    /// Whether this record sorts before `next` by more than a tie
+    /// breaker, which tells duplicates apart from records in order.
+    fn precedes(&self, next: &Self) -> bool {
+        self < next
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()>;
+
+    fn read(reader: &mut impl Read) -> io::Result<Self>;
+}
+
+/// A converted entry of a value dictionary with its old index, which
+/// only breaks ties. It is stored as its datatype, its old index, the
+/// length of its bytes and the bytes themselves.
+impl SortRecord for (TypedDictEntry, u64) {
+    fn memory(&self) -> usize {
+        self.0.as_buf().remaining() + ENTRY_OVERHEAD
+    }
+
+    fn precedes(&self, next: &Self) -> bool {
+        self.0 < next.0
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let (entry, old_ix) = self;
+        let bytes = entry.to_bytes();
+        writer.write_all(&[entry.datatype() as u8])?;
+        writer.write_all(&old_ix.to_be_bytes())?;
+        writer.write_all(&(bytes.len() as u64).to_be_bytes())?;
+        writer.write_all(&bytes)
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut header = [0; 17];
+        reader.read_exact(&mut header)?;
+        let datatype = Datatype::from_u8(header[0]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown datatype {} in sorted run", header[0]),
+            )
+        })?;
+        let old_ix = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let len = u64::from_be_bytes(header[9..].try_into().unwrap());
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes)?;
+        let entry = TypedDictEntry::new(datatype, SizedDictEntry::from(Bytes::from(bytes)));
+
+        Ok((entry, old_ix))
+    }
+}
+
+/// A triple, stored as its subject, predicate and object.
+impl SortRecord for IdTriple {
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.subject.to_be_bytes())?;
+        writer.write_all(&self.predicate.to_be_bytes())?;
+        writer.write_all(&self.object.to_be_bytes())
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut bytes = [0; 24];
+        reader.read_exact(&mut bytes)?;
+        let id = |ix: usize| u64::from_be_bytes(bytes[ix * 8..(ix + 1) * 8].try_into().unwrap());
+
+        Ok(IdTriple::new(id(0), id(1), id(2)))
+    }
+}
+
+/// Sorts records within a memory limit. Records are buffered in
+/// memory, and whenever the buffer outgrows the limit, it is sorted and
+/// spilled to a run file in the scratch directory. The runs are merged
+/// at the end.
+pub struct ExternalSorter<'a, R: SortRecord> {
+    scratch: &'a ScratchDir,
+    memory_limit: usize,
+    /// memory held elsewhere that counts against the limit
+    reserved_bytes: usize,
+    buffer: Vec<R>,
+    buffered_bytes: usize,
+    runs: Vec<PathBuf>,
+    /// the number of run files written, to name the next one
+    runs_written: usize,
+    /// the last record of the last run, so that we can tell whether
+    /// the records were pushed in order.
+    last_spilled: Option<R>,
+    in_order: bool,
+}
+
+impl<'a, R: SortRecord> ExternalSorter<'a, R> {
+    pub fn new(scratch: &'a ScratchDir, memory_limit: usize) -> Self {
+        Self {
+            scratch,
+            memory_limit,
+            reserved_bytes: 0,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            runs: Vec::new(),
+            runs_written: 0,
+            last_spilled: None,
+            in_order: true,
+        }
+    }
+
+    pub fn push(&mut self, record: R) -> io::Result<()> {
+        let last = self.buffer.last().or(self.last_spilled.as_ref());
+        if let Some(last) = last {
+            if !last.precedes(&record) {
+                self.in_order = false;
+            }
+        }
+
+        self.buffered_bytes += record.memory();
+        self.buffer.push(record);
+        if self.buffered_bytes > self.buffer_limit() {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    /// Count `bytes` of memory that is held elsewhere while sorting
+    /// against the memory limit, so that the buffer spills sooner.
+    pub fn reserve(&mut self, bytes: usize) {
+        self.reserved_bytes += bytes;
+    }
+
+    fn buffer_limit(&self) -> usize {
+        let limit = self.memory_limit.saturating_sub(self.reserved_bytes);
+        limit.max(MIN_BUFFER.min(self.memory_limit))
+    }
+
+    /// Whether every record was pushed after a record that precedes
+    /// it, which means that sorting does not change anything and that
+    /// there are no duplicates.
+    pub fn is_in_order(&self) -> bool {
+        self.in_order
+    }
+
+    /// The number of runs that were spilled so far.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if !self.in_order {
+            self.buffer.sort_unstable();
+        }
+        let mut writer = self.create_run()?;
+        for record in self.buffer.iter() {
+            record.write(&mut writer.writer)?;
+        }
+
+        self.runs.push(writer.finish()?);
+        if self.in_order {
+            self.last_spilled = self.buffer.pop();
+        }
+        self.buffer.clear();
+        self.buffered_bytes = 0;
+
+        Ok(())
+    }
+
+    /// All pushed records, sorted.
+    pub fn finish(mut self) -> io::Result<SortedRecords<R>> {
+        if self.runs.is_empty() {
+            if !self.in_order {
+                self.buffer.sort_unstable();
+            }
+            return Ok(SortedRecords::Buffered(self.buffer.into_iter()));
+        }
+
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        // merge the runs into fewer, longer runs until they can all be
+        // merged at once.
+        while self.runs.len() > MAX_FAN_IN {
+            let runs = std::mem::take(&mut self.runs);
+            for group in runs.chunks(MAX_FAN_IN) {
+                let mut writer = self.create_run()?;
+                for record in SortedRecords::<R>::merge(group)? {
+                    record?.write(&mut writer.writer)?;
+                }
+                self.runs.push(writer.finish()?);
+                for path in group {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+
+        SortedRecords::merge(&self.runs)
+    }
+
+    fn create_run(&mut self) -> io::Result<RunWriter> {
+        let (path, file) = self.scratch.create_file(&format!("run-{}", self.runs_written))?;
+        self.runs_written += 1;
+
+        Ok(RunWriter {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+/// The records of an [`ExternalSorter`], either sorted in memory or
+/// merged from the spilled runs.
+pub enum SortedRecords<R: SortRecord> {
+    Buffered(std::vec::IntoIter<R>),
+    Merged {
+        readers: Vec<RunReader<R>>,
+        heap: BinaryHeap<Reverse<(R, usize)>>,
+    },
+}
+
+impl<R: SortRecord> SortedRecords<R> {
+    fn merge(runs: &[PathBuf]) -> io::Result<Self> {
+        let mut readers = Vec::with_capacity(runs.len());
+        for path in runs.iter() {
+            readers.push(RunReader::open(path)?);
+        }
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for (run, reader) in readers.iter_mut().enumerate() {
+            if let Some(record) = reader.next_record()? {
+                heap.push(Reverse((record, run)));
+            }
+        }
+
+        Ok(SortedRecords::Merged { readers, heap })
+    }
+}
+
+impl<R: SortRecord> Iterator for SortedRecords<R> {
+    type Item = io::Result<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedRecords::Buffered(records) => records.next().map(Ok),
+            SortedRecords::Merged { readers, heap } => {
+                let Reverse((record, run)) = heap.pop()?;
+                match readers[run].next_record() {
+                    Ok(Some(next)) => heap.push(Reverse((next, run))),
+                    Ok(None) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+
+                Some(Ok(record))
+            }
+        }
+    }
+}
+
+struct RunWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl RunWriter {
+    fn finish(mut self) -> io::Result<PathBuf> {
+        self.writer.flush()?;
+
+        Ok(self.path)
+    }
+}
+
+/// Reads back a run written by [`ExternalSorter`].
+pub struct RunReader<R> {
+    reader: BufReader<File>,
+    record: PhantomData<R>,
+}
+
+impl<R: SortRecord> RunReader<R> {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+            record: PhantomData,
+        })
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<R>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        R::read(&mut self.reader).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::structure::TdbDataType;
+
+    fn scratch(name: &str) -> ScratchDir {
+        ScratchDir::new(
+            std::env::temp_dir().join(format!("escape-fixup-{name}-{}", std::process::id())),
+        )
+    }
+
+    fn sorted<R: SortRecord>(sorter: ExternalSorter<R>) -> Vec<R> {
+        sorter.finish().unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn spilled_runs_merge_into_sorted_entries() {
+        let scratch = scratch("spilled-runs");
+        let mut sorter = ExternalSorter::new(&scratch, 1000);
+        let mut expected = Vec::new();
+        for i in 0..500_u64 {
+            // out of order, with every value appearing twice
+            let entry = String::make_entry(&format!("value {}", (i * 7919) % 250));
+            expected.push((entry.clone(), i));
+            sorter.push((entry, i)).unwrap();
+        }
+        expected.sort();
+
+        assert!(!sorter.is_in_order());
+        assert!(sorter.runs() > 1);
+        assert_eq!(expected, sorted(sorter));
+        let path = scratch.path.clone();
+        drop(scratch);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn more_runs_than_fan_in_merge_in_passes() {
+        let scratch = scratch("many-runs");
+        let mut sorter = ExternalSorter::new(&scratch, 200);
+        let mut expected = Vec::new();
+        for i in 0..1000_u64 {
+            let entry = String::make_entry(&format!("value {}", (i * 7919) % 1000));
+            expected.push((entry.clone(), i));
+            sorter.push((entry, i)).unwrap();
+        }
+        expected.sort();
+
+        assert!(sorter.runs() > MAX_FAN_IN);
+        assert_eq!(expected, sorted(sorter));
+    }
+
+    #[test]
+    fn reserved_memory_spills_sooner() {
+        let scratch = scratch("reserved");
+        let mut sorter = ExternalSorter::new(&scratch, 4 << 20);
+        sorter.reserve(2 << 20);
+        // about 2.5 MiB of entries, which only fit without the reserved
+        // memory.
+        for i in 0..40_000_u32 {
+            sorter.push((u32::make_entry(&i), i as u64)).unwrap();
+        }
+
+        assert_eq!(1, sorter.runs());
+    }
+
+    #[test]
+    fn spilled_triples_merge_into_sorted_triples() {
+        let scratch = scratch("triples");
+        let mut sorter = ExternalSorter::new(&scratch, 1000);
+        let mut expected = Vec::new();
+        for i in 0..500_u64 {
+            let triple = IdTriple::new((i * 7919) % 50, i % 3, (i * 31) % 7);
+            expected.push(triple);
+            sorter.push(triple).unwrap();
+        }
+        expected.sort();
+
+        assert!(sorter.runs() > 1);
+        assert_eq!(expected, sorted(sorter));
+    }
+
+    #[test]
+    fn entries_in_order_stay_in_order() {
+        let scratch = scratch("in-order");
+        let mut sorter = ExternalSorter::new(&scratch, 1000);
+        let expected: Vec<_> = (0..100_u32).map(|i| (u32::make_entry(&i), i as u64)).collect();
+        for (entry, ix) in expected.iter() {
+            sorter.push((entry.clone(), *ix)).unwrap();
+        }
+
+        assert!(sorter.is_in_order());
+        assert!(sorter.runs() > 1);
+        assert_eq!(expected, sorted(sorter));
+    }
+}
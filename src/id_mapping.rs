@@ -0,0 +1,340 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use terminus_store::storage::directory::FileBackedStore;
+use terminus_store::storage::FileLoad;
+use terminus_store::structure::{LateLogArrayBufBuilder, LogArray, MonotonicLogArray};
+
+use std::io;
+use std::path::Path;
+
+/// Version of the parent map file format, written as its first word.
+const FORMAT_VERSION: u64 = 1;
+
+/// Maps the object ids of a v10 layer stack onto the object ids of the
+/// converted stack.
+///
+/// Values can move around within a layer when normalizing the strings
+/// changes their order, and they can collapse into one entry when two
+/// strings normalize to the same value. Collapsing shrinks the
+/// dictionary, so every id that comes after it, in this layer and all
+/// of its descendants, shifts down as well. The moved values are kept
+/// as explicit entries, while the shifts are kept as a list of ranges.
+///
+/// A mapping loaded from a parent map keeps the explicit entries of
+/// the parent as logarrays over the loaded file. Entries for the layers
+/// pushed on top of it are kept in memory until the mapping is written.
+#[derive(Default, Clone, Debug)]
+pub struct IdMapping {
+    /// node and value count of the stack before conversion
+    old_count: u64,
+    /// node and value count of the stack after conversion
+    new_count: u64,
+    /// `(first id, delta)`, sorted by first id. Every id from the first
+    /// id onwards, up to the next range, shifts down by delta.
+    shifts: Vec<(u64, u64)>,
+    /// ids that do not follow the shifts, as loaded from a parent map
+    loaded: Option<LoadedEntries>,
+    /// ids that do not follow the shifts, sorted by old id. These all
+    /// come after the loaded ones.
+    entries: Vec<(u64, u64)>,
+}
+
+#[derive(Clone, Debug)]
+struct LoadedEntries {
+    old_ids: MonotonicLogArray,
+    new_ids: LogArray,
+    /// the size of the parent map they are looked up in
+    size: usize,
+}
+
+impl IdMapping {
+    pub fn get(&self, id: u64) -> u64 {
+        if let Some(mapped) = self.get_entry(id) {
+            return mapped;
+        }
+
+        let ix = self.shifts.partition_point(|(first, _)| *first <= id);
+        if ix == 0 {
+            id
+        } else {
+            id - self.shifts[ix - 1].1
+        }
+    }
+
+    fn get_entry(&self, id: u64) -> Option<u64> {
+        if let Some(loaded) = &self.loaded {
+            if let Some(ix) = loaded.old_ids.index_of(id) {
+                return Some(loaded.new_ids.entry(ix));
+            }
+        }
+
+        self.entries
+            .binary_search_by_key(&id, |(old_id, _)| *old_id)
+            .ok()
+            .map(|ix| self.entries[ix].1)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.loaded.iter().all(|l| l.old_ids.is_empty())
+            && self.entries.is_empty()
+            && self.shifts.is_empty()
+    }
+
+    /// The memory held by the mapping: the parent map it was loaded
+    /// from and the entries and shifts added since.
+    pub fn memory(&self) -> usize {
+        let loaded = self.loaded.as_ref().map(|loaded| loaded.size).unwrap_or(0);
+        loaded + (self.entries.len() + self.shifts.len()) * std::mem::size_of::<(u64, u64)>()
+    }
+
+    /// The node and value count of the stack before conversion.
+    pub fn old_count(&self) -> u64 {
+        self.old_count
+    }
+
+    /// The node and value count of the stack after conversion.
+    pub fn new_count(&self) -> u64 {
+        self.new_count
+    }
+
+    /// Extend the mapping with the ids of a layer on top of the stack.
+    ///
+    /// `value_reordering` gives the new index of every old value index
+    /// if the value dictionary changed order or had entries collapsed,
+    /// or `None` if it did not.
+    pub fn push_layer(
+        &mut self,
+        node_count: u64,
+        old_value_count: u64,
+        new_value_count: u64,
+        value_reordering: Option<&[u64]>,
+    ) {
+        let delta = self.push_shift();
+
+        let old_value_base = self.old_count + node_count;
+        let new_value_base = self.new_count + node_count;
+        if let Some(value_reordering) = value_reordering {
+            for (old_ix, new_ix) in value_reordering.iter().enumerate() {
+                let old_id = old_value_base + old_ix as u64 + 1;
+                let new_id = new_value_base + new_ix + 1;
+                if old_id - delta != new_id {
+                    self.entries.push((old_id, new_id));
+                }
+            }
+        }
+
+        self.old_count = old_value_base + old_value_count;
+        self.new_count = new_value_base + new_value_count;
+    }
+
+    /// Extend the mapping with the ids of a layer that carries a
+    /// node/value id map, so its ids are not simply its nodes followed
+    /// by its values.
+    ///
+    /// `outer_remap` gives the new id of every old id in the layer,
+    /// both counted from the top of the stack below it.
+    pub fn push_remapped_layer(&mut self, new_layer_count: u64, outer_remap: &[u64]) {
+        let delta = self.push_shift();
+
+        for (old_ix, new_id) in outer_remap.iter().enumerate() {
+            let old_id = self.old_count + old_ix as u64 + 1;
+            let new_id = self.new_count + new_id;
+            if old_id - delta != new_id {
+                self.entries.push((old_id, new_id));
+            }
+        }
+
+        self.old_count += outer_remap.len() as u64;
+        self.new_count += new_layer_count;
+    }
+
+    /// Start a new shift range for the ids above the stack if the
+    /// stack shrunk, returning the current shift.
+    fn push_shift(&mut self) -> u64 {
+        let delta = self.old_count - self.new_count;
+        if delta != 0 && self.shifts.last().map(|(_, d)| *d) != Some(delta) {
+            self.shifts.push((self.old_count + 1, delta));
+        }
+
+        delta
+    }
+
+    /// The ids that do not follow the shifts, sorted by old id.
+    fn iter_entries(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.loaded
+            .iter()
+            .flat_map(|loaded| loaded.old_ids.iter().zip(loaded.new_ids.iter()))
+            .chain(self.entries.iter().copied())
+    }
+
+    /// Serialize the mapping as a parent map. All words are big endian,
+    /// and every part starts at a multiple of 8 bytes:
+    ///
+    /// - format version
+    /// - old count, new count
+    /// - number of shifts, followed by the shifts as `(first id, delta)`
+    /// - size in bytes of the old ids logarray
+    /// - logarray of the old ids of the explicit entries, sorted
+    /// - logarray of the new ids of the explicit entries
+    pub fn to_bytes(&self) -> Bytes {
+        let mut old_ids = LateLogArrayBufBuilder::new(BytesMut::new());
+        let mut new_ids = LateLogArrayBufBuilder::new(BytesMut::new());
+        for (old_id, new_id) in self.iter_entries() {
+            old_ids.push(old_id);
+            new_ids.push(new_id);
+        }
+        let old_ids = old_ids.finalize();
+        let new_ids = new_ids.finalize();
+
+        let mut buf = BytesMut::new();
+        buf.put_u64(FORMAT_VERSION);
+        buf.put_u64(self.old_count);
+        buf.put_u64(self.new_count);
+        buf.put_u64(self.shifts.len() as u64);
+        for (first, delta) in self.shifts.iter() {
+            buf.put_u64(*first);
+            buf.put_u64(*delta);
+        }
+        buf.put_u64(old_ids.len() as u64);
+        buf.put(old_ids);
+        buf.put(new_ids);
+
+        buf.freeze()
+    }
+
+    /// Parse a parent map written by [`IdMapping::to_bytes`]. The
+    /// explicit entries are not copied, but looked up in `bytes`.
+    pub fn parse(mut bytes: Bytes) -> io::Result<IdMapping> {
+        let size = bytes.len();
+        let version = read_word(&mut bytes)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported parent map format version {version}"
+            )));
+        }
+        let old_count = read_word(&mut bytes)?;
+        let new_count = read_word(&mut bytes)?;
+        let shift_count = read_word(&mut bytes)?;
+        if shift_count > bytes.remaining() as u64 / 16 {
+            return Err(invalid_data("parent map is truncated".to_string()));
+        }
+        let shifts = (0..shift_count)
+            .map(|_| Ok((read_word(&mut bytes)?, read_word(&mut bytes)?)))
+            .collect::<io::Result<_>>()?;
+        let old_ids_size = read_word(&mut bytes)?;
+        if old_ids_size > bytes.remaining() as u64 {
+            return Err(invalid_data("parent map is truncated".to_string()));
+        }
+        let old_ids = MonotonicLogArray::parse(bytes.split_to(old_ids_size as usize))?;
+        let new_ids = LogArray::parse(bytes)?;
+        if old_ids.len() != new_ids.len() {
+            return Err(invalid_data(format!(
+                "parent map has {} old ids but {} new ids",
+                old_ids.len(),
+                new_ids.len()
+            )));
+        }
+
+        Ok(IdMapping {
+            old_count,
+            new_count,
+            shifts,
+            loaded: Some(LoadedEntries {
+                old_ids,
+                new_ids,
+                size,
+            }),
+            entries: Vec::new(),
+        })
+    }
+
+    /// Load the parent map at `path`. The whole file is read into
+    /// memory, so callers count [`IdMapping::memory`] against the memory
+    /// limit.
+    pub async fn load(path: &Path) -> io::Result<IdMapping> {
+        Self::parse(FileBackedStore::new(path).map().await?)
+    }
+}
+
+fn read_word(bytes: &mut Bytes) -> io::Result<u64> {
+    if bytes.remaining() < 8 {
+        return Err(invalid_data("parent map is truncated".to_string()));
+    }
+
+    Ok(bytes.get_u64())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_mapping() -> IdMapping {
+        let mut mapping = IdMapping::default();
+        // two nodes, values 3 and 4 swap, 5 and 6 collapse
+        mapping.push_layer(2, 5, 4, Some(&[1, 0, 2, 2, 3]));
+        mapping.push_layer(1, 2, 2, Some(&[1, 0]));
+        mapping
+    }
+
+    #[test]
+    fn parent_map_round_trip() {
+        let mapping = example_mapping();
+        let loaded = IdMapping::parse(mapping.to_bytes()).unwrap();
+
+        assert_eq!(mapping.old_count(), loaded.old_count());
+        assert_eq!(mapping.new_count(), loaded.new_count());
+        for id in 1..=mapping.old_count() + 3 {
+            assert_eq!(mapping.get(id), loaded.get(id), "id {id}");
+        }
+        assert_eq!(4, loaded.get(3));
+        assert_eq!(5, loaded.get(6));
+        assert_eq!(9, loaded.get(9));
+        assert_eq!(8, loaded.get(10));
+    }
+
+    #[test]
+    fn loaded_parent_map_can_be_extended() {
+        let mut loaded = IdMapping::parse(example_mapping().to_bytes()).unwrap();
+        loaded.push_layer(0, 2, 2, Some(&[1, 0]));
+        let reloaded = IdMapping::parse(loaded.to_bytes()).unwrap();
+
+        assert_eq!(12, reloaded.old_count());
+        assert_eq!(11, reloaded.new_count());
+        assert_eq!(4, reloaded.get(3));
+        assert_eq!(11, reloaded.get(11));
+        assert_eq!(10, reloaded.get(12));
+    }
+
+    #[test]
+    fn loaded_parent_map_counts_as_memory() {
+        let bytes = example_mapping().to_bytes();
+        let mut loaded = IdMapping::parse(bytes.clone()).unwrap();
+        let memory = loaded.memory();
+        assert!(memory >= bytes.len());
+
+        loaded.push_layer(0, 2, 2, Some(&[1, 0]));
+        assert!(loaded.memory() > memory);
+    }
+
+    #[test]
+    fn identity_parent_map_round_trip() {
+        let mut mapping = IdMapping::default();
+        mapping.push_layer(3, 2, 2, None);
+        let loaded = IdMapping::parse(mapping.to_bytes()).unwrap();
+
+        assert!(loaded.is_identity());
+        assert_eq!(5, loaded.get(5));
+    }
+
+    #[test]
+    fn truncated_parent_map_is_invalid() {
+        let bytes = example_mapping().to_bytes();
+        for len in [0, 20, bytes.len() - 8] {
+            let e = IdMapping::parse(bytes.slice(..len)).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, e.kind(), "length {len}");
+        }
+    }
+}
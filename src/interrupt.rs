@@ -0,0 +1,87 @@
+use tokio::sync::Notify;
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Asks a running conversion to stop. After the first request, no new
+/// layers are started, but the layers in progress get to finish. After
+/// a second request, the layers in progress are aborted as well.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    inner: Arc<InterruptInner>,
+}
+
+#[derive(Debug, Default)]
+struct InterruptInner {
+    requests: AtomicUsize,
+    notify: Notify,
+}
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self) {
+        self.inner.requests.fetch_add(1, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    /// How many times an interruption was requested.
+    pub fn requests(&self) -> usize {
+        self.inner.requests.load(Ordering::SeqCst)
+    }
+
+    /// Wait until more than `seen` interruptions were requested.
+    pub async fn requested_after(&self, seen: usize) {
+        loop {
+            // created before the check, so that a request in between is
+            // not missed.
+            let notified = self.inner.notify.notified();
+            if self.requests() > seen {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Request an interruption whenever the process receives SIGINT or
+    /// SIGTERM, instead of exiting right away. `on_signal` is called
+    /// with the number of requests so far after every signal.
+    #[cfg(unix)]
+    pub fn listen_for_signals(&self, on_signal: impl Fn(usize) + Send + 'static) -> io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let interrupt = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sigint.recv() => {}
+                    _ = sigterm.recv() => {}
+                }
+                interrupt.request();
+                on_signal(interrupt.requests());
+            }
+        });
+        Ok(())
+    }
+
+    /// Request an interruption whenever the process receives Ctrl-C,
+    /// instead of exiting right away. `on_signal` is called with the
+    /// number of requests so far after every signal.
+    #[cfg(not(unix))]
+    pub fn listen_for_signals(&self, on_signal: impl Fn(usize) + Send + 'static) -> io::Result<()> {
+        let interrupt = self.clone();
+        tokio::spawn(async move {
+            // Ctrl-C can only fail to be listened for on the first
+            // call, in which case the process just exits on Ctrl-C.
+            while tokio::signal::ctrl_c().await.is_ok() {
+                interrupt.request();
+                on_signal(interrupt.requests());
+            }
+        });
+        Ok(())
+    }
+}
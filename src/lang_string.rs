@@ -0,0 +1,276 @@
+use std::borrow::Cow;
+
+use thiserror::Error;
+
+use crate::dataconversion::string_to_prolog_string;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LangStringParseError {
+    #[error("no @ separator found in langString `{0}`")]
+    MissingSeparator(String),
+    #[error("unterminated language tag in langString `{0}`")]
+    UnterminatedLang(String),
+    #[error("unterminated value in langString `{0}`")]
+    UnterminatedValue(String),
+    #[error("unexpected characters after the value in langString `{0}`")]
+    TrailingCharacters(String),
+}
+
+/// Split a langString as TerminusDB 10 stored it into its language tag
+/// and value.
+///
+/// Both parts may be unquoted, or quoted with single or double
+/// quotes. Inside quotes, a doubled quote character is turned into a
+/// backslash-escaped one, but escape sequences are otherwise left as
+/// they are, so both the language tag and the value still need to be
+/// decoded with
+/// [`prolog_string_to_string`](crate::dataconversion::prolog_string_to_string).
+pub fn parse_prolog_lang_string(s: &str) -> Result<(Cow<'_, str>, Cow<'_, str>), LangStringParseError> {
+    let (lang, rest) = match quote_char(s) {
+        Some(quote) => {
+            let (lang, end) = parse_quoted(s, quote)
+                .ok_or_else(|| LangStringParseError::UnterminatedLang(s.to_string()))?;
+            (lang, &s[end..])
+        }
+        None => {
+            let pos = s
+                .find('@')
+                .ok_or_else(|| LangStringParseError::MissingSeparator(s.to_string()))?;
+            (Cow::Borrowed(&s[..pos]), &s[pos..])
+        }
+    };
+
+    let rest = rest
+        .strip_prefix('@')
+        .ok_or_else(|| LangStringParseError::MissingSeparator(s.to_string()))?;
+
+    let value = match quote_char(rest) {
+        Some(quote) => {
+            let (value, end) = parse_quoted(rest, quote)
+                .ok_or_else(|| LangStringParseError::UnterminatedValue(s.to_string()))?;
+            if end != rest.len() {
+                return Err(LangStringParseError::TrailingCharacters(s.to_string()));
+            }
+            value
+        }
+        None => Cow::Borrowed(rest),
+    };
+
+    Ok((lang, value))
+}
+
+fn quote_char(s: &str) -> Option<char> {
+    match s.chars().next() {
+        Some(c @ ('\'' | '\"')) => Some(c),
+        _ => None,
+    }
+}
+
+/// Parse the quoted text at the start of `s`, returning its contents
+/// and the byte position just after the closing quote.
+fn parse_quoted(s: &str, quote: char) -> Option<(Cow<'_, str>, usize)> {
+    let mut result: Option<String> = None;
+    let mut characters = s.char_indices().skip(1).peekable();
+    while let Some((ix, c)) = characters.next() {
+        if c == '\\' {
+            let (_, escaped) = characters.next()?;
+            if let Some(result) = result.as_mut() {
+                result.push(c);
+                result.push(escaped);
+            }
+        } else if c == quote {
+            if characters.next_if(|(_, c)| *c == quote).is_some() {
+                let result = result.get_or_insert_with(|| s[1..ix].to_string());
+                result.push('\\');
+                result.push(quote);
+            } else {
+                let contents = match result {
+                    Some(result) => Cow::Owned(result),
+                    None => Cow::Borrowed(&s[1..ix]),
+                };
+                return Some((contents, ix + 1));
+            }
+        } else if let Some(result) = result.as_mut() {
+            result.push(c);
+        }
+    }
+
+    None
+}
+
+/// Encode a language tag and value in the `lang@"value"` form that
+/// TerminusDB 10 stored. The language tag is quoted if Prolog would
+/// not read it back as a plain atom.
+pub fn lang_string_to_prolog_lang_string(lang: &str, value: &str) -> String {
+    let value = string_to_prolog_string(value);
+    let mut result = String::with_capacity(lang.len() + value.len() + 5);
+    if is_unquoted_atom(lang) {
+        result.push_str(lang);
+    } else {
+        push_quoted_atom(&mut result, lang);
+    }
+    result.push_str("@\"");
+    result.push_str(&value);
+    result.push('\"');
+
+    result
+}
+
+/// Quote an atom the way SWI-Prolog does, which escapes single quotes
+/// rather than double quotes.
+fn push_quoted_atom(result: &mut String, s: &str) {
+    result.push('\'');
+    for c in s.chars() {
+        match c {
+            '\'' => result.push_str("\\'"),
+            '\"' => result.push(c),
+            c => result.push_str(&string_to_prolog_string(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    result.push('\'');
+}
+
+fn is_unquoted_atom(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_lowercase() => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> (String, String) {
+        let (lang, value) = parse_prolog_lang_string(s).unwrap();
+        (lang.into_owned(), value.into_owned())
+    }
+
+    fn pair(lang: &str, value: &str) -> (String, String) {
+        (lang.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn unquoted_lang_double_quoted_value() {
+        assert_eq!(pair("en", "hello"), parse("en@\"hello\""));
+    }
+
+    #[test]
+    fn single_quoted_lang() {
+        assert_eq!(pair("en-US", "hello"), parse("'en-US'@\"hello\""));
+    }
+
+    #[test]
+    fn double_quoted_lang() {
+        assert_eq!(pair("en", "hello"), parse("\"en\"@\"hello\""));
+    }
+
+    #[test]
+    fn single_quoted_value() {
+        assert_eq!(pair("en", "hello"), parse("en@'hello'"));
+    }
+
+    #[test]
+    fn unquoted_value() {
+        assert_eq!(pair("en", "hello"), parse("en@hello"));
+    }
+
+    #[test]
+    fn at_sign_in_value() {
+        assert_eq!(pair("en", "me@example.com"), parse("en@\"me@example.com\""));
+    }
+
+    #[test]
+    fn at_sign_in_quoted_lang() {
+        assert_eq!(pair("x@y", "hello"), parse("'x@y'@\"hello\""));
+    }
+
+    #[test]
+    fn empty_lang() {
+        assert_eq!(pair("", "hello"), parse("@\"hello\""));
+        assert_eq!(pair("", "hello"), parse("''@\"hello\""));
+    }
+
+    #[test]
+    fn empty_value() {
+        assert_eq!(pair("en", ""), parse("en@\"\""));
+    }
+
+    #[test]
+    fn escapes_are_kept() {
+        assert_eq!(pair("en", "a\\\"b\\nc"), parse("en@\"a\\\"b\\nc\""));
+    }
+
+    #[test]
+    fn doubled_quotes_become_escapes() {
+        assert_eq!(pair("it\\'s", "it\\'s"), parse("'it''s'@'it''s'"));
+    }
+
+    #[test]
+    fn quotes_in_lang_are_escaped() {
+        assert_eq!(
+            "'it\\'s \\\\ \"x\"'@\"v\"",
+            lang_string_to_prolog_lang_string("it's \\ \"x\"", "v")
+        );
+    }
+
+    #[test]
+    fn missing_separator() {
+        assert_eq!(
+            Err(LangStringParseError::MissingSeparator("en".to_string())),
+            parse_prolog_lang_string("en")
+        );
+        assert_eq!(
+            Err(LangStringParseError::MissingSeparator("'en'\"x\"".to_string())),
+            parse_prolog_lang_string("'en'\"x\"")
+        );
+    }
+
+    #[test]
+    fn unterminated_lang() {
+        assert_eq!(
+            Err(LangStringParseError::UnterminatedLang("'en@\"x\"".to_string())),
+            parse_prolog_lang_string("'en@\"x\"")
+        );
+    }
+
+    #[test]
+    fn unterminated_value() {
+        assert_eq!(
+            Err(LangStringParseError::UnterminatedValue("en@\"x\\\"".to_string())),
+            parse_prolog_lang_string("en@\"x\\\"")
+        );
+    }
+
+    #[test]
+    fn trailing_characters() {
+        assert_eq!(
+            Err(LangStringParseError::TrailingCharacters("en@\"x\"y".to_string())),
+            parse_prolog_lang_string("en@\"x\"y")
+        );
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let cases = [
+            ("en", "hello"),
+            ("en-US", "a \"b\"\n"),
+            ("", "x"),
+            ("it's", "x"),
+            ("a\\b\"c", "x"),
+        ];
+        for (lang, value) in cases {
+            let encoded = lang_string_to_prolog_lang_string(lang, value);
+            let (parsed_lang, parsed_value) = parse(&encoded);
+            assert_eq!(
+                lang,
+                crate::dataconversion::prolog_string_to_string(&parsed_lang).unwrap()
+            );
+            assert_eq!(
+                value,
+                crate::dataconversion::prolog_string_to_string(&parsed_value).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use clap::ValueEnum;
+use terminus_store::storage::name_to_string;
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use tokio::fs;
+
+/// How the layers of a store are laid out on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StoreLayout {
+    /// Every layer is a single `.larch` archive file
+    #[default]
+    Archive,
+    /// Every layer is a directory of files, as written by older installs
+    Directory,
+}
+
+impl fmt::Display for StoreLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Archive => "archive",
+            Self::Directory => "directory",
+        };
+        f.write_str(name)
+    }
+}
+
+impl StoreLayout {
+    /// Work out the layout of the store at `path` by looking at its
+    /// first layer. A store without layers is taken to be an archive
+    /// store.
+    pub async fn detect(path: &str) -> io::Result<StoreLayout> {
+        let mut prefixes = fs::read_dir(path).await?;
+        while let Some(prefix) = prefixes.next_entry().await? {
+            let is_prefix = prefix.file_name().len() == 3 && prefix.file_type().await?.is_dir();
+            if !is_prefix {
+                continue;
+            }
+            let mut layers = fs::read_dir(prefix.path()).await?;
+            while let Some(layer) = layers.next_entry().await? {
+                let name = layer.file_name();
+                let name = name.to_string_lossy();
+                if name.ends_with(".larch") {
+                    return Ok(StoreLayout::Archive);
+                } else if name.len() == 40 && layer.file_type().await?.is_dir() {
+                    return Ok(StoreLayout::Directory);
+                }
+            }
+        }
+
+        Ok(StoreLayout::Archive)
+    }
+
+    /// The path of a layer in the store at `store`. This is the archive
+    /// file or the layer directory, depending on the layout.
+    pub fn layer_path(self, store: &str, id: [u32; 5]) -> PathBuf {
+        let name = name_to_string(id);
+        let mut pathbuf = PathBuf::from(store);
+        pathbuf.push(&name[..3]);
+        match self {
+            StoreLayout::Archive => pathbuf.push(format!("{name}.larch")),
+            StoreLayout::Directory => pathbuf.push(name),
+        }
+
+        pathbuf
+    }
+
+    /// The path of the file that links a layer to its rollup.
+    pub fn rollup_path(self, store: &str, id: [u32; 5]) -> PathBuf {
+        let name = name_to_string(id);
+        let mut pathbuf = PathBuf::from(store);
+        pathbuf.push(&name[..3]);
+        match self {
+            StoreLayout::Archive => pathbuf.push(format!("{name}.rollup.hex")),
+            StoreLayout::Directory => {
+                pathbuf.push(name);
+                pathbuf.push("rollup.hex");
+            }
+        }
+
+        pathbuf
+    }
+
+    /// Remove a layer, and the link to its rollup, from the store at
+    /// `store`. A layer that does not exist is not an error.
+    pub async fn remove_layer(self, store: &str, id: [u32; 5]) -> io::Result<()> {
+        ignore_not_found(fs::remove_file(self.rollup_path(store, id)).await)?;
+        let layer_path = self.layer_path(store, id);
+        match self {
+            StoreLayout::Archive => ignore_not_found(fs::remove_file(layer_path).await),
+            StoreLayout::Directory => ignore_not_found(fs::remove_dir_all(layer_path).await),
+        }
+    }
+    /// Replace a layer in the store at `store` with the layer of the
+    /// same name in the store at `staging`, which has to be on the same
+    /// filesystem. The link to the rollup of the old layer is removed.
+    pub async fn replace_layer(self, staging: &str, store: &str, id: [u32; 5]) -> io::Result<()> {
+        ignore_not_found(fs::remove_file(self.rollup_path(store, id)).await)?;
+        let staged_path = self.layer_path(staging, id);
+        let layer_path = self.layer_path(store, id);
+        fs::create_dir_all(layer_path.parent().unwrap()).await?;
+        match self {
+            // renaming a file over another replaces it at once
+            StoreLayout::Archive => fs::rename(staged_path, layer_path).await,
+            StoreLayout::Directory => {
+                // a directory can only be renamed to a free name, so the
+                // old layer is moved out of the way first.
+                let mut old_path = layer_path.clone().into_os_string();
+                old_path.push(".old");
+                ignore_not_found(fs::rename(&layer_path, &old_path).await)?;
+                fs::rename(staged_path, &layer_path).await?;
+                ignore_not_found(fs::remove_dir_all(old_path).await)
+            }
+        }
+    }
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -1,10 +1,22 @@
-mod reachable;
+pub mod reachable;
 mod conversion_consts;
 mod convert_triples;
-mod convert_layer;
+pub mod convert_layer;
 pub mod convert_store;
-mod convert_dictionary;
-mod dataconversion;
+pub mod dry_run;
+pub mod verify;
+pub mod observer;
+pub mod layout;
+pub mod preflight;
+pub mod lock;
+pub mod interrupt;
+pub mod convert_dictionary;
+mod external_sort;
+mod dict_file_builder;
+mod id_mapping;
+mod staging;
+pub mod dataconversion;
+pub mod lang_string;
 
 /*
 pub async fn convert_store(in_store_path: PathBuf, out_store_path: PathBuf, conversion_datetime: DateTime<Local>) -> io::Result<()> {
@@ -0,0 +1,111 @@
+use chrono::Local;
+use fs2::FileExt;
+use thiserror::Error;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the lock file taken in the workdir and the target store.
+pub const LOCK_FILE: &str = ".conversion.lock";
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("`{dir}` is in use by process {pid}, which started at {started}")]
+    Held {
+        dir: String,
+        pid: String,
+        started: String,
+    },
+    #[error("`{0}` is in use by another process")]
+    HeldByUnknown(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// An exclusive advisory lock on a directory, held until dropped. The
+/// lock file records the process holding it, so that a second
+/// invocation can say who it is waiting for.
+#[derive(Debug)]
+pub struct DirectoryLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DirectoryLock {
+    /// Lock the directory `dir`, creating it if it does not exist. This
+    /// fails rather than waits if another process holds the lock.
+    pub fn acquire(dir: &str) -> Result<Self, LockError> {
+        fs::create_dir_all(dir)?;
+        let mut path = PathBuf::from(dir);
+        path.push(LOCK_FILE);
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            if file.try_lock_exclusive().is_err() {
+                return Err(holder(dir, &mut file));
+            }
+            // the previous holder removes the file when it is done, so
+            // we may have locked a file that is no longer there.
+            if !is_same_file(&file, &path)? {
+                continue;
+            }
+
+            file.set_len(0)?;
+            file.rewind()?;
+            writeln!(file, "{}", std::process::id())?;
+            writeln!(file, "{}", Local::now().to_rfc3339())?;
+            file.sync_all()?;
+
+            return Ok(Self { file, path });
+        }
+    }
+}
+
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        // removing the file before unlocking it makes sure no one locks
+        // a file that is about to disappear. The directory may have been
+        // moved or removed already, which is fine.
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+fn holder(dir: &str, file: &mut File) -> LockError {
+    let mut contents = String::new();
+    if file.read_to_string(&mut contents).is_ok() {
+        let mut lines = contents.lines();
+        if let (Some(pid), Some(started)) = (lines.next(), lines.next()) {
+            return LockError::Held {
+                dir: dir.to_string(),
+                pid: pid.to_string(),
+                started: started.to_string(),
+            };
+        }
+    }
+
+    LockError::HeldByUnknown(dir.to_string())
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let file_metadata = file.metadata()?;
+
+    Ok(metadata.dev() == file_metadata.dev() && metadata.ino() == file_metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> io::Result<bool> {
+    Ok(path.exists())
+}
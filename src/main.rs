@@ -1,49 +1,399 @@
-use std::path::PathBuf;
+use chrono::{DateTime, FixedOffset};
+use clap::{Args, Parser, Subcommand};
+use std::num::NonZeroUsize;
+use std::time::SystemTime;
 
-use chrono::{DateTime, Local};
-use clap::Parser;
-
-use terminusdb_10_to_11_escape_fixup::convert_store::convert_store;
+use terminus_store::storage::archive::ArchiveLayerStore;
+use terminus_store::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
+use terminus_store::storage::{name_to_string, string_to_name};
+use terminusdb_10_to_11_escape_fixup::convert_layer::convert_layer;
+use terminusdb_10_to_11_escape_fixup::convert_store::*;
+use terminusdb_10_to_11_escape_fixup::dataconversion::MalformedEscapePolicy;
+use terminusdb_10_to_11_escape_fixup::dry_run::dry_run_store;
+use terminusdb_10_to_11_escape_fixup::interrupt::Interrupt;
+use terminusdb_10_to_11_escape_fixup::layout::StoreLayout;
+use terminusdb_10_to_11_escape_fixup::lock::DirectoryLock;
+use terminusdb_10_to_11_escape_fixup::observer::PrintObserver;
+use terminusdb_10_to_11_escape_fixup::reachable::find_reachable_layers;
+use terminusdb_10_to_11_escape_fixup::verify::verify_store;
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
-    from: String,
-    to: String,
-    date: String,
-    /// The workdir to store mappings in
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert all reachable layers of a store
+    Convert {
+        from: String,
+        to: String,
+        /// Layers created after this RFC 3339 timestamp are copied instead of converted. Not used with --reverse
+        #[arg(value_parser = DateTime::parse_from_rfc3339, required_unless_present = "reverse")]
+        date: Option<DateTime<FixedOffset>>,
+        /// Keep going with other layers if a layer does not convert
+        #[arg(short = 'c', long = "continue")]
+        keep_going: bool,
+        /// Replace original directory with converted directory
+        #[arg(short = 'r', long = "replace")]
+        replace: bool,
+        /// Cleanup work directory after successful run
+        #[arg(short = 'k', long = "clean")]
+        clean: bool,
+        /// Report what the conversion would change without writing anything
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// How many layers to convert at the same time
+        #[arg(short = 'j', long = "jobs", default_value = "1")]
+        jobs: NonZeroUsize,
+        /// Convert even if the storage version of the store says it is converted already, or if it does not seem to fit on disk
+        #[arg(long = "force")]
+        force: bool,
+        /// Memory for sorting dictionary entries before they are spilled to the workdir, like 512M or 4G
+        #[arg(long = "memory-limit", value_parser = parse_size, default_value = "1G")]
+        memory_limit: usize,
+        #[command(flatten)]
+        escapes: EscapeOptions,
+        #[command(flatten)]
+        common: CommonOptions,
+    },
+    /// Convert a single layer, replacing what was converted of it before once the conversion succeeds
+    ConvertLayer {
+        from: String,
+        to: String,
+        /// The layer to convert. Its parent has to be converted already
+        #[arg(value_parser = string_to_name)]
+        id: [u32; 5],
+        /// If the layer was created after this RFC 3339 timestamp, it is copied instead of converted. Not used with --reverse
+        #[arg(value_parser = DateTime::parse_from_rfc3339, required_unless_present = "reverse")]
+        date: Option<DateTime<FixedOffset>>,
+        /// Convert even if the storage version of the store says it is converted already
+        #[arg(long = "force")]
+        force: bool,
+        /// Memory for sorting dictionary entries before they are spilled to the workdir, like 512M or 4G
+        #[arg(long = "memory-limit", value_parser = parse_size, default_value = "1G")]
+        memory_limit: usize,
+        #[command(flatten)]
+        escapes: EscapeOptions,
+        #[command(flatten)]
+        common: CommonOptions,
+    },
+    /// List the reachable layers of a store, each followed by the layer it is converted after
+    Reachable {
+        from: String,
+        #[command(flatten)]
+        common: CommonOptions,
+    },
+    /// Summarize the progress of a conversion
+    Status {
+        to: String,
+        #[command(flatten)]
+        common: CommonOptions,
+    },
+    /// Check the converted store against the original
+    Verify {
+        from: String,
+        to: String,
+        /// Layers created after this RFC 3339 timestamp are expected to be copied. Not used with --reverse
+        #[arg(value_parser = DateTime::parse_from_rfc3339, required_unless_present = "reverse")]
+        date: Option<DateTime<FixedOffset>>,
+        #[command(flatten)]
+        escapes: EscapeOptions,
+        #[command(flatten)]
+        common: CommonOptions,
+    },
+    /// Remove the work directory of a conversion
+    Clean {
+        to: String,
+        #[command(flatten)]
+        common: CommonOptions,
+    },
+    /// Undo `convert --replace`, moving the converted store back to `to` and restoring the backup
+    Rollback {
+        from: String,
+        /// The backup directory that `convert --replace` reported
+        backup: String,
+        to: String,
+        #[command(flatten)]
+        common: CommonOptions,
+    },
+}
+
+#[derive(Args)]
+struct CommonOptions {
+    /// The workdir to store mappings in, `<to>/.workdir` by default
     #[arg(short = 'w', long = "workdir")]
     workdir: Option<String>,
-    /// Convert the store assuming all values are strings
-    /// Keep going with other layers if a layer does not convert
-    #[arg(short = 'c', long = "continue")]
-    keep_going: bool,
     /// Verbose reporting
     #[arg(short = 'v', long = "verbose")]
     verbose: bool,
-    /// Replace original directory with converted directory
-    #[arg(short = 'r', long = "replace")]
-    replace: bool,
-    /// Cleanup work directory after successful run
-    #[arg(short = 'k', long = "clean")]
-    clean: bool,
+    /// How the layers of the store are laid out, detected from the store by default
+    #[arg(long = "layout", value_enum)]
+    layout: Option<StoreLayout>,
+}
+
+/// How strings are converted, for the commands that convert them.
+#[derive(Args)]
+struct EscapeOptions {
+    /// What to do with escape sequences that cannot be decoded
+    #[arg(short = 'm', long = "malformed-escapes", value_enum, default_value_t)]
+    malformed_escapes: MalformedEscapePolicy,
+    /// Convert a version 11 store back into a version 10 store
+    #[arg(long = "reverse")]
+    reverse: bool,
 }
 
+impl CommonOptions {
+    fn workdir(&self, to: &str) -> String {
+        self.workdir.clone().unwrap_or_else(|| format!("{to}/.workdir"))
+    }
+
+    async fn layout(&self, from: &str) -> StoreLayout {
+        let layout = match self.layout {
+            Some(layout) => layout,
+            None => StoreLayout::detect(from).await.unwrap(),
+        };
+        if self.verbose {
+            println!("Store `{from}` has the {layout} layout");
+        }
+        layout
+    }
+}
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
-    let Cli{from, to, workdir, keep_going, verbose, replace, clean, ..} = Cli::parse();
-    //let date_converted = DateTime::parse_from_rfc3339(&cli.date).unwrap().naive_local().and_local_timezone(Local).unwrap();
-    let default_workdir = format!("{to}/.workdir");
-    convert_store(
-        &from,
-        &to,
-        workdir.as_deref().unwrap_or(&default_workdir),
-        keep_going,
-        verbose,
-        replace,
-        clean,
-    )
-        .await.unwrap();
+    match Cli::parse().command {
+        Command::Convert{from, to, date, keep_going, replace, clean, dry_run, jobs, force, memory_limit, escapes, common} => {
+            let layout = common.layout(&from).await;
+            let options = ConversionOptions::new(&common.workdir(&to), cutoff(date))
+                .keep_going(keep_going)
+                .verbose(common.verbose)
+                .replace(replace)
+                .clean(clean)
+                .policy(escapes.malformed_escapes)
+                .reverse(escapes.reverse)
+                .jobs(jobs.get())
+                .layout(layout)
+                .force(force)
+                .memory_limit(memory_limit);
+            if dry_run {
+                let summary = dry_run_store(&from, &options).await.unwrap();
+                if !summary.failures.is_empty() {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            let interrupt = Interrupt::new();
+            let listening = interrupt.listen_for_signals(|requests| {
+                if requests == 1 {
+                    eprintln!("Interrupted, finishing the layers in progress. Interrupt again to abort them.");
+                } else {
+                    eprintln!("Interrupted again, aborting the layers in progress.");
+                }
+            });
+            if let Err(e) = listening {
+                eprintln!("ERROR: could not listen for signals: {e}");
+            }
+            let work = common.workdir(&to);
+            let version_name = if escapes.reverse { "10" } else { "11" };
+            match convert_store(&from, &to, &options.interrupt(interrupt)).await {
+                Ok(report) => {
+                    match report.backup {
+                        Some(backup) => {
+                            println!("Version {version_name} Store now available");
+                            println!("Backup storage directory is in `{backup}`");
+                        }
+                        None => println!("Your version {version_name} Store is converted in `{to}`, you will need to manually move it to the target storage location: `{from}`"),
+                    }
+                    println!("Conversion completed!");
+                    if !clean {
+                        println!("You can now remove your workdir: `{work}`");
+                    }
+                }
+                Err(StoreConversionError::Interrupted) => {
+                    println!("Conversion interrupted, progress is saved in `{work}`");
+                    println!("To resume, run: {}", resume_command());
+                    std::process::exit(130);
+                }
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::ConvertLayer{from, to, id, date, force, memory_limit, escapes, common} => {
+            let work = common.workdir(&to);
+            let layout = common.layout(&from).await;
+            // held until the layer is converted
+            let _locks = match DirectoryLock::acquire(&work).and_then(|work_lock| Ok((work_lock, DirectoryLock::acquire(&to)?))) {
+                Ok(locks) => locks,
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+            };
+            let observer = PrintObserver::new(common.verbose);
+            let mut status_log = status_log(&work, &observer).await.unwrap();
+            let previous_status = get_status_hashmap(&work).await.unwrap().get(&id).copied();
+            write_status(&mut status_log, id, ConversionStatus::Started).await.unwrap();
+            let options = ConversionOptions::new(&work, cutoff(date))
+                .verbose(common.verbose)
+                .policy(escapes.malformed_escapes)
+                .reverse(escapes.reverse)
+                .layout(layout)
+                .force(force)
+                .memory_limit(memory_limit);
+            match convert_layer(&from, &to, &options, &name_to_string(id)).await {
+                Ok(fallbacks) => {
+                    for fallback in fallbacks {
+                        println!("{fallback}");
+                    }
+                    write_status(&mut status_log, id, ConversionStatus::Completed).await.unwrap();
+                }
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    // the old layer is only replaced once the new one is
+                    // complete, so a completed layer is still completed.
+                    let status = match previous_status {
+                        Some(ConversionStatus::Completed) => ConversionStatus::Completed,
+                        _ => ConversionStatus::Error,
+                    };
+                    write_status(&mut status_log, id, status).await.unwrap();
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Reachable{from, common} => {
+            let label_store = DirectoryLabelStore::new(&from);
+            let observer = PrintObserver::new(common.verbose);
+            let reachable = match common.layout(&from).await {
+                StoreLayout::Archive => find_reachable_layers(&ArchiveLayerStore::new(&from), &label_store, &observer).await,
+                StoreLayout::Directory => find_reachable_layers(&DirectoryLayerStore::new(&from), &label_store, &observer).await,
+            }
+            .unwrap();
+            for (parent, children) in reachable.iter() {
+                for child in children {
+                    match parent {
+                        Some(parent) => println!("{} {}", name_to_string(*child), name_to_string(*parent)),
+                        None => println!("{}", name_to_string(*child)),
+                    }
+                }
+            }
+        }
+        Command::Status{to, common} => {
+            let journal = match read_status_journal(&common.workdir(&to)).await {
+                Ok(journal) => journal,
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+            };
+            let mut completed = 0;
+            let mut started = 0;
+            let mut failed = 0;
+            let mut interrupted = 0;
+            let mut unfinished = Vec::new();
+            for (layer, status) in journal.statuses.iter() {
+                match status {
+                    ConversionStatus::Completed => completed += 1,
+                    ConversionStatus::Started => started += 1,
+                    ConversionStatus::Error => failed += 1,
+                    ConversionStatus::Interrupted => interrupted += 1,
+                }
+                if *status != ConversionStatus::Completed {
+                    unfinished.push((name_to_string(*layer), status));
+                }
+            }
+            unfinished.sort_by(|(l1, _), (l2, _)| l1.cmp(l2));
+            for (layer, status) in unfinished.iter() {
+                println!("{layer} {status}");
+            }
+            println!("{completed} layers completed, {started} started, {failed} failed, {interrupted} interrupted");
+            println!("{} journal records", journal.records);
+            if journal.torn_records != 0 {
+                println!("{} torn records at the end of the journal, these are removed when the conversion resumes", journal.torn_records);
+            }
+        }
+        Command::Verify{from, to, date, escapes, common} => {
+            let options = ConversionOptions::new(&common.workdir(&to), cutoff(date))
+                .verbose(common.verbose)
+                .policy(escapes.malformed_escapes)
+                .reverse(escapes.reverse)
+                .layout(common.layout(&from).await);
+            let summary = verify_store(&from, &to, &options).await.unwrap();
+            if !summary.divergences.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Clean{to, common} => {
+            let work = common.workdir(&to);
+            // a conversion in progress holds the lock, and its workdir
+            // must not be removed from under it.
+            let _lock = match DirectoryLock::acquire(&work) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("ERROR: {e}");
+                    std::process::exit(1);
+                }
+            };
+            clean_workdir(&work).await.unwrap();
+            if common.verbose {
+                println!("Workdir `{work}` removed");
+            }
+        }
+        Command::Rollback{from, backup, to, common} => {
+            restore_storage_directory(&from, &backup, &to).await.unwrap();
+            if common.verbose {
+                println!("Converted store moved back to `{to}`");
+            }
+            println!("Original store restored in `{from}`");
+        }
+    }
+}
+
+/// The cutoff for a date argument. The date can only be left out when
+/// reversing, which does not use the cutoff.
+fn cutoff(date: Option<DateTime<FixedOffset>>) -> SystemTime {
+    date.map_or(SystemTime::UNIX_EPOCH, SystemTime::from)
+}
+
+/// Parse a number of bytes, optionally followed by K, M, G or T for
+/// kibibytes, mebibytes, gibibytes or tebibytes.
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (number, shift) = match size.char_indices().last() {
+        Some((ix, 'k' | 'K')) => (&size[..ix], 10),
+        Some((ix, 'm' | 'M')) => (&size[..ix], 20),
+        Some((ix, 'g' | 'G')) => (&size[..ix], 30),
+        Some((ix, 't' | 'T')) => (&size[..ix], 40),
+        _ => (size, 0),
+    };
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("`{size}` is not a size like 512M or 4G"))?;
+
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("`{size}` is too large"))
+}
 
+/// The command line this process was started with, quoted for a POSIX
+/// shell. Converting again with the same arguments resumes the
+/// conversion.
+fn resume_command() -> String {
+    std::env::args()
+        .map(|arg| {
+            let is_plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=+,@%".contains(c));
+            if is_plain {
+                arg
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
@@ -0,0 +1,343 @@
+use terminus_store::storage::name_to_string;
+
+use crate::convert_dictionary::{DictionaryConversionError, EscapeFallback, ValueDictAnalysis};
+use crate::convert_layer::LayerConversionError;
+use crate::dataconversion::MalformedEscapePolicy;
+use crate::dry_run::DryRunSummary;
+use crate::preflight::{format_size, ConversionEstimate, SpaceShortage};
+use crate::verify::VerifySummary;
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Progress made while finding the reachable layers of a store.
+#[derive(Debug, Clone, Copy)]
+pub enum ReachabilityProgress {
+    /// Label retrieval has started.
+    Started,
+    /// All labels were retrieved.
+    LabelsRetrieved(usize),
+    /// A layer was visited while walking the layer stacks.
+    LayerVisited([u32; 5]),
+    /// All reachable layers were found.
+    LayersRetrieved(usize),
+    /// The reachable layers were grouped by the layer they are
+    /// converted after.
+    LayersSorted,
+}
+
+/// What is done with a layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerAction {
+    Convert,
+    /// The layer was written after the cutoff, so it is copied rather
+    /// than converted.
+    Copy,
+}
+
+/// A step in converting or copying a single layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerProgress {
+    /// The ids of the parent were mapped to their converted ids.
+    ParentMapRetrieved,
+    /// The converted values are out of order, so they are sorted again.
+    Reordering,
+    /// This many sorted runs spilled to the workdir are being merged.
+    MergingRuns(usize),
+    DictionariesConverted,
+    IdMapConverted,
+    TriplesConverted,
+    /// Values that became equal were collapsed into one, which made
+    /// triples referring to them duplicates as well.
+    Collapsed { values: u64, triples: u64 },
+    FilesCopied,
+    IndexesRebuilt,
+    ParentMapWritten,
+    /// The layer is copied, but its ancestors were renumbered, so its
+    /// ids are remapped.
+    Remapping,
+    /// The layer was copied as it is.
+    LayerFilesCopied,
+}
+
+/// Something the conversion removed.
+#[derive(Debug, Clone, Copy)]
+pub enum Removal<'a> {
+    /// What was converted of a layer that did not complete.
+    Layer([u32; 5]),
+    /// A file or directory left behind by an earlier run.
+    Unfinished(&'a Path),
+    /// The parent map of a layer, once nothing reads it anymore.
+    ParentMap([u32; 5]),
+    /// The workdir, once the conversion is done.
+    Workdir(&'a str),
+}
+
+/// What a dry run found converting a layer would do.
+#[derive(Debug, Clone, Copy)]
+pub enum DryRunOutcome<'a> {
+    /// The layer was written after the cutoff, so it would be copied.
+    Copied,
+    Converted(&'a ValueDictAnalysis),
+    Failed(&'a DictionaryConversionError),
+}
+
+/// Something that does not stop the conversion, but may need looking
+/// into.
+#[derive(Debug, Clone)]
+pub enum ConversionWarning {
+    /// The source does not have the storage version the conversion
+    /// expects, but it is converted anyway.
+    UnexpectedStorageVersion { found: u64, expected: u64 },
+    /// Sorting may take more memory than is available.
+    InsufficientMemory { needed: u64, available: u64 },
+    /// A filesystem does not have the space the conversion needs, but
+    /// it is converted anyway.
+    InsufficientSpace(SpaceShortage),
+    /// Torn records were removed from the end of the status journal.
+    TornRecordsRemoved { records: usize, path: PathBuf },
+}
+
+impl fmt::Display for ConversionWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionWarning::UnexpectedStorageVersion { found, expected } => write!(
+                f,
+                "converting a store with storage version {found}, while version {expected} was expected"
+            ),
+            ConversionWarning::InsufficientMemory { needed, available } => write!(
+                f,
+                "sorting may take {} of memory, but only {} is available. Consider a lower --memory-limit",
+                format_size(*needed),
+                format_size(*available)
+            ),
+            ConversionWarning::InsufficientSpace(shortage) => write!(f, "{shortage}"),
+            ConversionWarning::TornRecordsRemoved { records, path } => write!(
+                f,
+                "removing {records} torn records from the end of `{}`",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Receives the progress of a store conversion. Every method does
+/// nothing by default, so an implementation only needs the events it
+/// cares about.
+///
+/// Layers are converted concurrently, so events for different layers
+/// may arrive from different tasks and interleave.
+pub trait ConversionObserver: Send + Sync {
+    fn reachability_progress(&self, _progress: ReachabilityProgress) {}
+
+    fn layer_started(&self, _layer: [u32; 5], _action: LayerAction) {}
+
+    fn layer_finished(&self, _layer: [u32; 5], _fallbacks: &[EscapeFallback]) {}
+
+    fn layer_failed(&self, _layer: [u32; 5], _error: &LayerConversionError) {}
+
+    /// The layer was already converted by an earlier run.
+    fn layer_skipped(&self, _layer: [u32; 5]) {}
+
+    fn layer_progress(&self, _layer: [u32; 5], _progress: LayerProgress) {}
+
+    fn removed(&self, _removal: Removal<'_>) {}
+
+    fn warning(&self, _warning: &ConversionWarning) {}
+
+    /// What the conversion is expected to take, before it starts.
+    fn estimated(&self, _estimate: &ConversionEstimate) {}
+
+    /// Every layer was converted, and `count` malformed escape
+    /// sequences got the fallback of `policy`, as listed in `log`.
+    fn fallbacks_applied(&self, _policy: MalformedEscapePolicy, _count: usize, _log: &Path) {}
+
+    /// A dry run worked out what converting the layer would do.
+    fn dry_run_layer(&self, _layer: [u32; 5], _outcome: DryRunOutcome<'_>) {}
+
+    fn dry_run_finished(&self, _summary: &DryRunSummary) {}
+
+    /// The converted layer was compared with the original layer. It is
+    /// as expected if there are no `divergences`.
+    fn layer_verified(&self, _layer: [u32; 5], _divergences: &[String]) {}
+
+    fn verification_finished(&self, _summary: &VerifySummary) {}
+}
+
+/// Reports progress on standard output, the way the command line tool
+/// does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrintObserver {
+    verbose: bool,
+}
+
+impl PrintObserver {
+    pub fn new(verbose: bool) -> Self {
+        Self { verbose }
+    }
+}
+
+impl ConversionObserver for PrintObserver {
+    fn reachability_progress(&self, progress: ReachabilityProgress) {
+        if !self.verbose {
+            return;
+        }
+        match progress {
+            ReachabilityProgress::Started => println!("starting label retrieval"),
+            ReachabilityProgress::LabelsRetrieved(_) => println!("labels retrieved"),
+            ReachabilityProgress::LayerVisited(_) => {
+                print!(".");
+                let _ = io::stdout().flush();
+            }
+            ReachabilityProgress::LayersRetrieved(_) => println!("reachable layers retrieved"),
+            ReachabilityProgress::LayersSorted => println!("reachable layers sorted"),
+        }
+    }
+
+    fn layer_started(&self, layer: [u32; 5], action: LayerAction) {
+        match action {
+            LayerAction::Convert => println!("converting layer {}", name_to_string(layer)),
+            LayerAction::Copy => println!("copying layer {}", name_to_string(layer)),
+        }
+    }
+
+    fn layer_finished(&self, _layer: [u32; 5], fallbacks: &[EscapeFallback]) {
+        if let Some(fallback) = fallbacks.first() {
+            println!(
+                "applied {} fallback to {} malformed escape sequences",
+                fallback.policy,
+                fallbacks.len()
+            );
+        }
+    }
+
+    fn layer_failed(&self, _layer: [u32; 5], error: &LayerConversionError) {
+        eprintln!("ERROR: {error}");
+    }
+
+    fn layer_skipped(&self, layer: [u32; 5]) {
+        if self.verbose {
+            println!("skipping: {}", name_to_string(layer));
+        }
+    }
+
+    fn layer_progress(&self, _layer: [u32; 5], progress: LayerProgress) {
+        let step = match progress {
+            LayerProgress::Reordering => {
+                eprintln!(" reordering..");
+                return;
+            }
+            LayerProgress::MergingRuns(runs) => {
+                eprintln!(" merging {runs} sorted runs..");
+                return;
+            }
+            LayerProgress::Collapsed { values, triples } => {
+                println!("collapsed {values} duplicate values, removing {triples} duplicate triples");
+                return;
+            }
+            LayerProgress::ParentMapRetrieved => "parent mappings retrieved",
+            LayerProgress::DictionariesConverted => "dictionaries converted",
+            LayerProgress::IdMapConverted => "node/value id map converted",
+            LayerProgress::TriplesConverted => "triples converted",
+            LayerProgress::FilesCopied => "files copied",
+            LayerProgress::IndexesRebuilt => "indexes rebuilt",
+            LayerProgress::ParentMapWritten => "written parent map to workdir",
+            LayerProgress::Remapping => "ancestors were renumbered, remapping ids",
+            LayerProgress::LayerFilesCopied => "layer files copied",
+        };
+        if self.verbose {
+            println!("{step}");
+        }
+    }
+
+    fn removed(&self, removal: Removal<'_>) {
+        match removal {
+            Removal::Layer(layer) => println!("layer cleanup: {}", name_to_string(layer)),
+            Removal::Unfinished(path) => println!("removed unfinished `{}`", path.display()),
+            Removal::ParentMap(layer) if self.verbose => {
+                println!("removed parent map of {}", name_to_string(layer))
+            }
+            Removal::Workdir(work) if self.verbose => println!("Workdir `{work}` removed"),
+            Removal::ParentMap(_) | Removal::Workdir(_) => {}
+        }
+    }
+
+    fn warning(&self, warning: &ConversionWarning) {
+        eprintln!("WARNING: {warning}");
+    }
+
+    fn estimated(&self, estimate: &ConversionEstimate) {
+        if self.verbose {
+            println!(
+                "estimated to need {} in the target, {} in the workdir and {} of memory",
+                format_size(estimate.target),
+                format_size(estimate.workdir),
+                format_size(estimate.memory)
+            );
+        }
+    }
+
+    fn fallbacks_applied(&self, policy: MalformedEscapePolicy, count: usize, log: &Path) {
+        println!(
+            "Applied {policy} fallback to {count} malformed escape sequences, see `{}`",
+            log.display()
+        );
+    }
+
+    fn dry_run_layer(&self, layer: [u32; 5], outcome: DryRunOutcome<'_>) {
+        let name = name_to_string(layer);
+        match outcome {
+            DryRunOutcome::Copied => {
+                if self.verbose {
+                    println!("{name}: would be copied");
+                }
+            }
+            DryRunOutcome::Converted(analysis) => {
+                let ValueDictAnalysis {
+                    num_entries,
+                    changed,
+                    moved,
+                    duplicates,
+                    fallbacks,
+                } = analysis;
+                if self.verbose || *changed != 0 || *fallbacks != 0 {
+                    println!("{name}: {changed} of {num_entries} values change, {moved} move, {duplicates} collapse, {fallbacks} fallbacks");
+                }
+            }
+            DryRunOutcome::Failed(error) => println!("{name}: would fail: {error}"),
+        }
+    }
+
+    fn dry_run_finished(&self, summary: &DryRunSummary) {
+        println!(
+            "{} layers, {} copied, {} reordered, {} failing",
+            summary.layers,
+            summary.copied,
+            summary.reordered_layers,
+            summary.failures.len()
+        );
+        println!(
+            "{} values change, {} move, {} collapse, {} fallbacks",
+            summary.changed, summary.moved, summary.duplicates, summary.fallbacks
+        );
+    }
+
+    fn layer_verified(&self, layer: [u32; 5], divergences: &[String]) {
+        let name = name_to_string(layer);
+        if divergences.is_empty() && self.verbose {
+            println!("{name}: ok");
+        }
+        for divergence in divergences {
+            println!("{name}: {divergence}");
+        }
+    }
+
+    fn verification_finished(&self, summary: &VerifySummary) {
+        println!(
+            "{} layers verified, {} divergences",
+            summary.layers,
+            summary.divergences.len()
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use terminus_store::storage::consts::{FILENAMES, FILENAME_ENUM_MAP};
+use terminus_store::storage::{FileLoad, LayerStore, PersistentLayerStore};
+use terminus_store::structure::logarray_file_get_length_and_width;
+use terminus_store::structure::tfc::block::BLOCK_SIZE;
+
+use crate::convert_layer::path_for_parent_map;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+
+use tokio::fs;
+
+/// A parent map stores an old and a new id for every value that moves,
+/// in logarrays of at most one word per id.
+const PARENT_MAP_BYTES_PER_VALUE: u64 = 16;
+/// A sorted run stores the datatype, the old index and the length of
+/// every entry besides its bytes.
+const RUN_BYTES_PER_VALUE: u64 = 17;
+
+/// What converting the unfinished layers of a store is expected to
+/// take, as estimated by [`estimate_conversion`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConversionEstimate {
+    /// Bytes written to the target, which is about the size of the
+    /// unfinished layers in the source.
+    pub target: u64,
+    /// Peak bytes of parent maps, sorted runs and layers under
+    /// construction in the workdir.
+    pub workdir: u64,
+    /// Peak memory used for the layers being converted and for sorting
+    /// their values and triples.
+    pub memory: u64,
+}
+
+/// What is known of a layer without loading it.
+#[derive(Debug, Clone, Copy)]
+struct LayerSizes {
+    parent: Option<[u32; 5]>,
+    /// the size of the files of the layer
+    size: u64,
+    /// an upper bound of the number of values in the value dictionary
+    values: u64,
+    /// the size of the blocks of the value dictionary
+    value_bytes: u64,
+}
+
+/// Estimate what converting the reachable layers that are not in
+/// `completed` takes, `jobs` layers at a time. This only reads the
+/// sizes of layer files, not their contents.
+///
+/// The parent map of a layer holds the entries of the parent map of
+/// its parent and those for the values of the layer that move. As there
+/// is no telling how many move before converting, every value of a
+/// layer is assumed to move, on top of the parent map in `work` that a
+/// completed ancestor left, if there is one. With parent maps being
+/// removed as soon as nothing reads them anymore, at most two of them
+/// are assumed to be in the workdir for every job: the one a layer
+/// reads and the one it writes.
+///
+/// Every job holds the files of the layer it converts in memory, as
+/// they are read whole, besides what it sorts. Archive layers are built
+/// in the workdir, so they take no memory of their own, but every job
+/// takes up the size of its layer in the workdir until the layer is
+/// packed. Layers are assumed to be archive layers.
+pub async fn estimate_conversion<S: PersistentLayerStore>(
+    store: &S,
+    work: &str,
+    reachable: &HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>,
+    completed: &HashSet<[u32; 5]>,
+    jobs: usize,
+    memory_limit: usize,
+) -> io::Result<ConversionEstimate> {
+    let mut sizes = HashMap::new();
+    for layer in reachable.values().flatten() {
+        let mut current = Some(*layer);
+        while let Some(layer) = current.filter(|layer| !sizes.contains_key(layer)) {
+            let layer_sizes = layer_sizes(store, layer).await?;
+            current = layer_sizes.parent;
+            sizes.insert(layer, layer_sizes);
+        }
+    }
+
+    let memory_per_job = (memory_limit / jobs) as u64;
+    let mut estimate = ConversionEstimate::default();
+    let mut parent_maps = Vec::new();
+    let mut runs = Vec::new();
+    let mut staged = Vec::new();
+    let mut job_memory = Vec::new();
+    for layer in reachable.values().flatten() {
+        if completed.contains(layer) {
+            continue;
+        }
+        let layer_sizes = sizes[layer];
+        estimate.target += layer_sizes.size;
+        staged.push(layer_sizes.size);
+        parent_maps.push(parent_map_size(work, &sizes, *layer).await?);
+        // entries are only spilled when they do not fit in memory, and
+        // they take up at least as much as their compressed blocks.
+        let entry_bytes = layer_sizes.value_bytes + layer_sizes.values * RUN_BYTES_PER_VALUE;
+        if entry_bytes > memory_per_job {
+            runs.push(entry_bytes);
+        }
+        job_memory.push(layer_sizes.size + entry_bytes.min(memory_per_job));
+    }
+
+    estimate.workdir =
+        largest(parent_maps, 2 * jobs) + largest(runs, jobs) + largest(staged, jobs);
+    estimate.memory = largest(job_memory, jobs);
+
+    Ok(estimate)
+}
+
+async fn layer_sizes<S: PersistentLayerStore>(
+    store: &S,
+    layer: [u32; 5],
+) -> io::Result<LayerSizes> {
+    let parent = LayerStore::get_layer_parent_name(store, layer).await?;
+    let size = layer_size(store, layer).await?;
+    let offsets = store
+        .get_file(layer, FILENAMES.value_dictionary_offsets)
+        .await?;
+    let blocks = store
+        .get_file(layer, FILENAMES.value_dictionary_blocks)
+        .await?;
+    if !offsets.exists().await? || !blocks.exists().await? {
+        return Ok(LayerSizes {
+            parent,
+            size,
+            values: 0,
+            value_bytes: 0,
+        });
+    }
+    // the last block offset is left out, and every block holds at most
+    // BLOCK_SIZE values.
+    let (offset_count, _) = logarray_file_get_length_and_width(offsets).await?;
+    let values = (offset_count as u64 + 1) * BLOCK_SIZE as u64;
+    let value_bytes = blocks.size().await? as u64;
+
+    Ok(LayerSizes {
+        parent,
+        size,
+        values,
+        value_bytes,
+    })
+}
+
+/// The size of all files of a layer, not counting the link to its
+/// rollup, which is a layer of its own.
+async fn layer_size<S: PersistentLayerStore>(store: &S, layer: [u32; 5]) -> io::Result<u64> {
+    let mut size = 0;
+    for name in FILENAME_ENUM_MAP.keys().filter(|name| **name != FILENAMES.rollup) {
+        if store.file_exists(layer, name).await? {
+            size += store.get_file(layer, name).await?.size().await? as u64;
+        }
+    }
+
+    Ok(size)
+}
+
+/// The size of the parent map that converting a layer writes: an entry
+/// for every value of the layer and of its ancestors, down to the
+/// nearest ancestor whose parent map is in `work`, plus that parent map.
+async fn parent_map_size(
+    work: &str,
+    sizes: &HashMap<[u32; 5], LayerSizes>,
+    layer: [u32; 5],
+) -> io::Result<u64> {
+    let mut values = 0;
+    let mut current = sizes.get(&layer);
+    while let Some(layer_sizes) = current {
+        values += layer_sizes.values;
+        let Some(parent) = layer_sizes.parent else {
+            break;
+        };
+        match fs::metadata(path_for_parent_map(work, parent)).await {
+            Ok(metadata) => return Ok(metadata.len() + values * PARENT_MAP_BYTES_PER_VALUE),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        current = sizes.get(&parent);
+    }
+
+    Ok(values * PARENT_MAP_BYTES_PER_VALUE)
+}
+
+/// The sum of the `count` largest sizes.
+fn largest(mut sizes: Vec<u64>, count: usize) -> u64 {
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes.into_iter().take(count).sum()
+}
+
+/// A filesystem that does not have the space a conversion needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpaceShortage {
+    /// the target or workdir on the filesystem
+    pub path: String,
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for SpaceShortage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` needs about {} but only has {} free",
+            self.path,
+            format_size(self.needed),
+            format_size(self.available)
+        )
+    }
+}
+
+/// The free space on the filesystem of a path, and which filesystem
+/// that is, if we can tell.
+#[derive(Debug, Clone, Copy)]
+struct FilesystemSpace {
+    device: Option<u64>,
+    available: u64,
+}
+
+async fn filesystem_space(path: &str) -> io::Result<FilesystemSpace> {
+    #[cfg(unix)]
+    let device = {
+        use std::os::unix::fs::MetadataExt;
+        Some(fs::metadata(path).await?.dev())
+    };
+    #[cfg(not(unix))]
+    let device = None;
+
+    Ok(FilesystemSpace {
+        device,
+        available: fs2::available_space(path)?,
+    })
+}
+
+/// Compare the estimate with the free space on the filesystems of the
+/// target and the workdir, which both have to exist.
+pub async fn check_space(
+    to: &str,
+    work: &str,
+    estimate: &ConversionEstimate,
+) -> io::Result<Vec<SpaceShortage>> {
+    let target = filesystem_space(to).await?;
+    let workdir = filesystem_space(work).await?;
+
+    Ok(space_shortages(to, target, work, workdir, estimate))
+}
+
+fn space_shortages(
+    to: &str,
+    target: FilesystemSpace,
+    work: &str,
+    workdir: FilesystemSpace,
+    estimate: &ConversionEstimate,
+) -> Vec<SpaceShortage> {
+    let same_filesystem = target.device.is_some() && target.device == workdir.device;
+    let checks = if same_filesystem {
+        vec![(to, target.available, estimate.target + estimate.workdir)]
+    } else {
+        vec![
+            (to, target.available, estimate.target),
+            (work, workdir.available, estimate.workdir),
+        ]
+    };
+
+    checks
+        .into_iter()
+        .filter(|(_, available, needed)| needed > available)
+        .map(|(path, available, needed)| SpaceShortage {
+            path: path.to_string(),
+            needed,
+            available,
+        })
+        .collect()
+}
+
+/// The memory that is available to the conversion without swapping,
+/// if we can tell.
+pub fn available_memory() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+        let line = meminfo
+            .lines()
+            .find(|line| line.starts_with("MemAvailable:"))?;
+        let kibibytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kibibytes * 1024)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Format a number of bytes the way sizes are given on the command
+/// line, like 512M or 4.2G.
+pub fn format_size(bytes: u64) -> String {
+    const SUFFIXES: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut suffix = 0;
+    while size >= 1024.0 && suffix < SUFFIXES.len() - 1 {
+        size /= 1024.0;
+        suffix += 1;
+    }
+
+    format!("{size:.1}{}", SUFFIXES[suffix])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::layer::ValueTriple;
+    use terminus_store::storage::memory::MemoryLayerStore;
+
+    fn space(device: u64, available: u64) -> FilesystemSpace {
+        FilesystemSpace {
+            device: Some(device),
+            available,
+        }
+    }
+
+    #[test]
+    fn target_and_workdir_are_checked_together_on_one_filesystem() {
+        let estimate = ConversionEstimate {
+            target: 600,
+            workdir: 300,
+            memory: 0,
+        };
+
+        let shortages = space_shortages("to", space(1, 800), "work", space(1, 800), &estimate);
+        assert_eq!(
+            vec![SpaceShortage {
+                path: "to".to_string(),
+                needed: 900,
+                available: 800
+            }],
+            shortages
+        );
+
+        let shortages = space_shortages("to", space(1, 800), "work", space(2, 800), &estimate);
+        assert!(shortages.is_empty());
+
+        let shortages = space_shortages("to", space(1, 500), "work", space(2, 200), &estimate);
+        assert_eq!(2, shortages.len());
+        assert_eq!("work", shortages[1].path);
+    }
+
+    #[tokio::test]
+    async fn parent_maps_build_on_the_parent_maps_in_the_workdir() {
+        let work = std::env::temp_dir().join(format!("escape-fixup-estimate-{}", std::process::id()));
+        let work = work.to_str().unwrap();
+        let (base, child, grandchild) = ([1, 0, 0, 0, 0], [2, 0, 0, 0, 0], [3, 0, 0, 0, 0]);
+        let layer = |parent, values| LayerSizes {
+            parent,
+            size: 0,
+            values,
+            value_bytes: 0,
+        };
+        let sizes = HashMap::from([
+            (base, layer(None, 100)),
+            (child, layer(Some(base), 10)),
+            (grandchild, layer(Some(child), 1)),
+        ]);
+
+        let size = parent_map_size(work, &sizes, grandchild).await.unwrap();
+        assert_eq!(111 * PARENT_MAP_BYTES_PER_VALUE, size);
+
+        // the base layer was converted by an earlier run
+        let path = path_for_parent_map(work, base);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [0; 40]).unwrap();
+        let size = parent_map_size(work, &sizes, grandchild).await.unwrap();
+        std::fs::remove_dir_all(work).unwrap();
+        assert_eq!(40 + 11 * PARENT_MAP_BYTES_PER_VALUE, size);
+    }
+
+    #[tokio::test]
+    async fn layers_under_construction_count_towards_the_workdir() {
+        let work = std::env::temp_dir().join(format!("escape-fixup-staged-estimate-{}", std::process::id()));
+        let work = work.to_str().unwrap();
+        let store = MemoryLayerStore::new();
+        let mut builder = store.create_base_layer().await.unwrap();
+        let base = builder.name();
+        builder.add_value_triple(ValueTriple::new_string_value("cow", "says", "moo"));
+        builder.commit_boxed().await.unwrap();
+
+        let reachable = HashMap::from([(None, vec![base])]);
+        let estimate = estimate_conversion(&store, work, &reachable, &HashSet::new(), 1, 1 << 30)
+            .await
+            .unwrap();
+        let sizes = HashMap::from([(base, layer_sizes(&store, base).await.unwrap())]);
+        let parent_map = parent_map_size(work, &sizes, base).await.unwrap();
+        assert!(estimate.target > 0);
+        assert_eq!(parent_map + estimate.target, estimate.workdir);
+    }
+
+    #[test]
+    fn sizes_are_formatted_like_command_line_sizes() {
+        assert_eq!("512", format_size(512));
+        assert_eq!("1.5K", format_size(1536));
+        assert_eq!("4.0G", format_size(4 << 30));
+        assert_eq!("2048.0T", format_size(2 << 50));
+    }
+}
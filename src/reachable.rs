@@ -1,18 +1,17 @@
 use itertools::*;
 use terminus_store::Layer;
-use terminus_store::storage::{LabelStore, LayerStore, string_to_name};
-use terminus_store::storage::archive::ArchiveLayerStore;
-use terminus_store::storage::directory::DirectoryLabelStore;
+use terminus_store::storage::{LabelStore, LayerStore, PersistentLayerStore, string_to_name};
 use terminus_store::structure::TypedDictEntry;
 
+use crate::observer::{ConversionObserver, ReachabilityProgress};
+
 use std::collections::{HashMap, HashSet};
 use std::io;
-use std::io::Write;
 
-pub async fn find_reachable_layers(
-    layer_store: &ArchiveLayerStore,
-    label_store: &DirectoryLabelStore,
-    verbose: bool,
+pub async fn find_reachable_layers<S: PersistentLayerStore, L: LabelStore + ?Sized>(
+    layer_store: &S,
+    label_store: &L,
+    observer: &dyn ConversionObserver,
 ) -> io::Result<HashMap<Option<[u32; 5]>, Vec<[u32; 5]>>> {
     let special_labels: HashSet<&'static str> = HashSet::from([
         "http%3a%2f%2fterminusdb.com%2fschema%2fref",
@@ -22,9 +21,7 @@ pub async fn find_reachable_layers(
         "terminusdb%3a%2f%2f%2fsystem%2fschema",
     ]);
 
-    if verbose {
-        println!("starting label retrieval");
-    }
+    observer.reachability_progress(ReachabilityProgress::Started);
     let labels = LabelStore::labels(label_store).await?;
     let special_layers: Vec<[u32; 5]> = labels
         .iter()
@@ -36,9 +33,9 @@ pub async fn find_reachable_layers(
         .filter(|l| !special_labels.contains(l.name.as_str()))
         .map(|l| l.layer.unwrap())
         .collect();
-    if verbose {
-        println!("labels retrieved");
-    }
+    observer.reachability_progress(ReachabilityProgress::LabelsRetrieved(
+        data_product_layers.len() + special_layers.len(),
+    ));
     data_product_layers.sort();
     data_product_layers.dedup();
     let mut layers = data_product_layers.clone();
@@ -70,41 +67,42 @@ pub async fn find_reachable_layers(
     discovered.extend(layers.clone());
 
     let mut final_list = Vec::with_capacity(layers.len());
+    let mut rollups = HashMap::new();
     while let Some(layer) = layers.pop() {
-        if verbose {
-            print!(".");
-            io::stdout().flush()?;
-        }
-        if let Some(parent) =
-            LayerStore::get_layer_parent_name(layer_store, layer).await?
-        {
-            final_list.push((Some(parent), layer));
+        observer.reachability_progress(ReachabilityProgress::LayerVisited(layer));
+        let parent = LayerStore::get_layer_parent_name(layer_store, layer).await?;
+        if let Some(parent) = parent {
             if discovered.insert(parent) {
                 layers.push(parent);
             }
-        } else {
-            final_list.push((None, layer));
         }
+        final_list.push((parent, layer));
 
-        /* We have not forgotten, but we just aren't doing it.
-        // we musn't forget about the rollup
+        // A rollup is scheduled as a child of the layer it rolls up
+        // rather than of its own parent, so that it is converted after
+        // the layer it stands in for.
         if PersistentLayerStore::layer_has_rollup(layer_store, layer).await? {
             let rollup =
                 PersistentLayerStore::read_rollup_file(layer_store, layer).await?;
-            if discovered.insert(rollup) {
-                layers.push(rollup);
+            if rollup != layer {
+                rollups.insert(rollup, layer);
+                final_list.push((Some(layer), rollup));
+                if discovered.insert(rollup) {
+                    layers.push(rollup);
+                }
             }
         }
-        */
     }
+    final_list.retain(|(parent, layer)| match rollups.get(layer) {
+        Some(rolled_up) => *parent == Some(*rolled_up),
+        None => true,
+    });
 
-    if verbose {
-        println!("reachable layers retrieved");
-    }
+    observer.reachability_progress(ReachabilityProgress::LayersRetrieved(discovered.len()));
     final_list.sort();
     let group_iter = final_list
         .into_iter()
-        .group_by(|(parent, _)| parent.clone());
+        .group_by(|(parent, _)| *parent);
     let final_map: HashMap<Option<[u32; 5]>, Vec<[u32; 5]>> = group_iter
         .into_iter()
         .map(|(k, g)| {
@@ -115,15 +113,13 @@ pub async fn find_reachable_layers(
         })
         .collect();
 
-    if verbose {
-        println!("reachable layers sorted");
-    }
+    observer.reachability_progress(ReachabilityProgress::LayersSorted);
 
     Ok(final_map)
 }
 
-async fn discover_layers_in_meta_graph(
-    store: &ArchiveLayerStore,
+async fn discover_layers_in_meta_graph<S: PersistentLayerStore>(
+    store: &S,
     id: [u32; 5],
 ) -> io::Result<Vec<[u32; 5]>> {
     let meta_layer = LayerStore::get_layer(store, id)
@@ -149,7 +145,6 @@ async fn discover_layers_in_meta_graph(
     Ok(result)
 }
 
-const STRING_SUFFIX: &'static str = "\"^^'http://www.w3.org/2001/XMLSchema#string'";
 fn layer_id_value_to_id(val: &TypedDictEntry) -> [u32; 5] {
     let id = val.as_val::<String,String>();
     string_to_name(&id).unwrap()
@@ -0,0 +1,323 @@
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use terminus_store::storage::archive::{
+    ArchiveFilePresenceHeader, ArchiveLayerHandle, ArchiveLayerHandleReader,
+    ArchiveLayerHandleWriter, ArchiveLayerStore,
+};
+use terminus_store::storage::consts::{LayerFileEnum, FILENAME_ENUM_MAP};
+use terminus_store::storage::directory::{DirectoryLayerStore, FileBackedStore};
+use terminus_store::storage::{FileLoad, FileStore, PersistentLayerStore, SyncableFile};
+use terminus_store::structure::LateLogArrayBufBuilder;
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+
+use crate::layout::StoreLayout;
+
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// An archive layer store that builds its layers as layer directories
+/// in the workdir rather than in memory. A layer is packed into its
+/// archive file when it is finalized, and its directory is removed.
+/// Layers that are not under construction are read from the archive
+/// store.
+#[derive(Clone)]
+pub struct StagedArchiveLayerStore {
+    archive: ArchiveLayerStore,
+    staging: DirectoryLayerStore,
+    path: String,
+    work: String,
+}
+
+impl StagedArchiveLayerStore {
+    pub fn new(path: &str, work: &str) -> Self {
+        Self {
+            archive: ArchiveLayerStore::new(path),
+            // named after their layer, so that a layer directory is
+            // cleaned up with the rest of an unfinished layer.
+            staging: DirectoryLayerStore::new(work),
+            path: path.to_string(),
+            work: work.to_string(),
+        }
+    }
+
+    async fn is_staged(&self, layer: [u32; 5]) -> io::Result<bool> {
+        self.staging.directory_exists(layer).await
+    }
+
+    /// Write the files of a staged layer into its archive file, in the
+    /// layout of `ArchiveLayerStore`: the presence header, the end
+    /// offset of every file and then the files, ordered by file type.
+    async fn pack(&self, layer: [u32; 5]) -> io::Result<()> {
+        let mut files = Vec::new();
+        for (name, file_type) in FILENAME_ENUM_MAP.iter() {
+            // the rollup is not part of the archive
+            if *file_type == LayerFileEnum::Rollup
+                || staged_name(name) != *name
+                || !self.staging.file_exists(layer, name).await?
+            {
+                continue;
+            }
+            let file = self.staging.get_file(layer, name).await?;
+            files.push((*file_type, file.size().await?, file));
+        }
+        files.sort_by_key(|(file_type, _, _)| *file_type);
+
+        let presence_header =
+            ArchiveFilePresenceHeader::from_present(files.iter().map(|(file_type, _, _)| *file_type));
+        let mut offsets = LateLogArrayBufBuilder::new(BytesMut::new());
+        let mut tally = 0;
+        for (_, size, _) in files.iter() {
+            tally += *size as u64;
+            offsets.push(tally);
+        }
+        let mut header = BytesMut::new();
+        header.put_u64(presence_header.inner());
+        header.extend(offsets.finalize_header_first());
+
+        let path = StoreLayout::Archive.layer_path(&self.path, layer);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut writer = BufWriter::new(File::create(&tmp_path).await?);
+        writer.write_all(&header).await?;
+        for (_, _, file) in files {
+            io::copy(&mut file.open_read().await?, &mut writer).await?;
+        }
+        writer.flush().await?;
+        writer.into_inner().sync_all().await?;
+
+        fs::rename(tmp_path, path).await
+    }
+}
+
+#[async_trait]
+impl PersistentLayerStore for StagedArchiveLayerStore {
+    type File = StagedFile;
+
+    /// The layers in the archive store. Layers under construction are
+    /// not listed.
+    async fn directories(&self) -> io::Result<Vec<[u32; 5]>> {
+        self.archive.directories().await
+    }
+
+    async fn create_named_directory(&self, name: [u32; 5]) -> io::Result<[u32; 5]> {
+        if self.archive.directory_exists(name).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "tried to create a new layer which already exists",
+            ));
+        }
+
+        self.staging.create_named_directory(name).await
+    }
+
+    async fn directory_exists(&self, name: [u32; 5]) -> io::Result<bool> {
+        Ok(self.is_staged(name).await? || self.archive.directory_exists(name).await?)
+    }
+
+    async fn get_file(&self, directory: [u32; 5], name: &str) -> io::Result<Self::File> {
+        // the rollup is a file of its own next to the archive, even
+        // while the layer is under construction.
+        if FILENAME_ENUM_MAP[name] != LayerFileEnum::Rollup && self.is_staged(directory).await? {
+            Ok(StagedFile::Staged(self.staging.get_file(directory, staged_name(name)).await?))
+        } else {
+            Ok(StagedFile::Archived(self.archive.get_file(directory, name).await?))
+        }
+    }
+
+    async fn file_exists(&self, directory: [u32; 5], file: &str) -> io::Result<bool> {
+        if FILENAME_ENUM_MAP[file] != LayerFileEnum::Rollup && self.is_staged(directory).await? {
+            self.staging.file_exists(directory, staged_name(file)).await
+        } else {
+            self.archive.file_exists(directory, file).await
+        }
+    }
+
+    async fn finalize(&self, directory: [u32; 5]) -> io::Result<()> {
+        self.pack(directory).await?;
+        fs::remove_dir_all(StoreLayout::Directory.layer_path(&self.work, directory)).await
+    }
+}
+
+/// The name a file is staged under. An archive stores the files of
+/// base and child layers that have the same file type under that type,
+/// so those are one file, staged under the first of their names.
+fn staged_name(name: &str) -> &'static str {
+    let file_type = FILENAME_ENUM_MAP[name];
+    FILENAME_ENUM_MAP
+        .iter()
+        .filter(|(_, other)| **other == file_type)
+        .map(|(name, _)| *name)
+        .min()
+        .unwrap()
+}
+
+/// A file of a [`StagedArchiveLayerStore`], either in a staged layer
+/// directory or in the archive store.
+#[derive(Clone)]
+pub enum StagedFile {
+    Staged(FileBackedStore),
+    Archived(ArchiveLayerHandle),
+}
+
+#[async_trait]
+impl FileLoad for StagedFile {
+    type Read = StagedFileReader;
+
+    async fn exists(&self) -> io::Result<bool> {
+        match self {
+            Self::Staged(file) => file.exists().await,
+            Self::Archived(file) => file.exists().await,
+        }
+    }
+
+    async fn size(&self) -> io::Result<usize> {
+        match self {
+            Self::Staged(file) => file.size().await,
+            Self::Archived(file) => file.size().await,
+        }
+    }
+
+    async fn open_read_from(&self, offset: usize) -> io::Result<Self::Read> {
+        match self {
+            Self::Staged(file) => Ok(StagedFileReader::Staged(file.open_read_from(offset).await?)),
+            Self::Archived(file) => Ok(StagedFileReader::Archived(file.open_read_from(offset).await?)),
+        }
+    }
+
+    async fn map(&self) -> io::Result<Bytes> {
+        match self {
+            Self::Staged(file) => file.map().await,
+            Self::Archived(file) => file.map().await,
+        }
+    }
+}
+
+#[async_trait]
+impl FileStore for StagedFile {
+    type Write = StagedFileWriter;
+
+    async fn open_write(&self) -> io::Result<Self::Write> {
+        match self {
+            Self::Staged(file) => Ok(StagedFileWriter::Staged(file.open_write().await?)),
+            Self::Archived(file) => Ok(StagedFileWriter::Archived(file.open_write().await?)),
+        }
+    }
+}
+
+pub enum StagedFileReader {
+    Staged(File),
+    Archived(ArchiveLayerHandleReader),
+}
+
+impl AsyncRead for StagedFileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Staged(reader) => Pin::new(reader).poll_read(cx, buf),
+            Self::Archived(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+pub enum StagedFileWriter {
+    Staged(BufWriter<File>),
+    Archived(ArchiveLayerHandleWriter),
+}
+
+impl AsyncWrite for StagedFileWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Staged(writer) => Pin::new(writer).poll_write(cx, buf),
+            Self::Archived(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Staged(writer) => Pin::new(writer).poll_flush(cx),
+            Self::Archived(writer) => Pin::new(writer).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Staged(writer) => Pin::new(writer).poll_shutdown(cx),
+            Self::Archived(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
+
+#[async_trait]
+impl SyncableFile for StagedFileWriter {
+    async fn sync_all(self) -> io::Result<()> {
+        match self {
+            Self::Staged(writer) => writer.sync_all().await,
+            Self::Archived(writer) => writer.sync_all().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use terminus_store::storage::consts::FILENAMES;
+
+    fn dir(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("escape-fixup-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    async fn write_layer<S: PersistentLayerStore>(store: &S, layer: [u32; 5]) {
+        store.create_named_directory(layer).await.unwrap();
+        let files: [(&str, &[u8]); 4] = [
+            (FILENAMES.value_dictionary_blocks, b"values"),
+            (FILENAMES.node_dictionary_blocks, b"nodes"),
+            (FILENAMES.pos_subjects, b"subjects"),
+            (FILENAMES.base_s_p_adjacency_list_nums, b"nums"),
+        ];
+        for (name, contents) in files {
+            let mut writer = store.get_file(layer, name).await.unwrap().open_write().await.unwrap();
+            writer.write_all(contents).await.unwrap();
+            writer.flush().await.unwrap();
+            writer.sync_all().await.unwrap();
+        }
+        // an archive has one file for the base and the positive files
+        // of the same type
+        assert!(store.file_exists(layer, FILENAMES.pos_s_p_adjacency_list_nums).await.unwrap());
+        store.finalize(layer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn staged_layer_is_packed_like_an_archive_layer() {
+        let layer = [1, 2, 3, 4, 5];
+        let (staged, archived, work) = (dir("staged"), dir("archived"), dir("staging-work"));
+        let store = StagedArchiveLayerStore::new(&staged, &work);
+        write_layer(&store, layer).await;
+        write_layer(&ArchiveLayerStore::new(&archived), layer).await;
+
+        let staged_archive = std::fs::read(StoreLayout::Archive.layer_path(&staged, layer)).unwrap();
+        let archive = std::fs::read(StoreLayout::Archive.layer_path(&archived, layer)).unwrap();
+        assert_eq!(archive, staged_archive);
+        assert!(!StoreLayout::Directory.layer_path(&work, layer).exists());
+        // once packed, the layer is read from the archive
+        let file = store.get_file(layer, FILENAMES.pos_subjects).await.unwrap();
+        assert!(matches!(file, StagedFile::Archived(_)));
+        assert_eq!(&b"subjects"[..], &file.map().await.unwrap()[..]);
+
+        for dir in [staged, archived, work] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use terminus_store::layer::{IdTriple, InternalLayer, Layer, ObjectType, ValueTriple};
+use terminus_store::storage::archive::ArchiveLayerStore;
+use terminus_store::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
+use terminus_store::storage::{LayerStore, PersistentLayerStore};
+use terminus_store::structure::{Datatype, LangString};
+
+use crate::convert_dictionary::convert_entry;
+use crate::convert_store::ConversionOptions;
+use crate::dataconversion::MalformedEscapePolicy;
+use crate::layout::StoreLayout;
+use crate::reachable::find_reachable_layers;
+
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+
+/// What [`verify_store`] found.
+#[derive(Debug, Default)]
+pub struct VerifySummary {
+    pub layers: u64,
+    /// Every divergence found, with the layer it was found in.
+    pub divergences: Vec<([u32; 5], String)>,
+}
+
+/// Compare every reachable layer of the original store with the
+/// converted layer in the target store. Each original triple has its
+/// object value converted the way `convert_store` would with `options`
+/// and is then looked up in the converted layer. Layers that were
+/// copied are compared unchanged. What is found is reported to the
+/// observer of `options`.
+pub async fn verify_store(from: &str, to: &str, options: &ConversionOptions) -> io::Result<VerifySummary> {
+    match options.layout {
+        StoreLayout::Archive => {
+            let v10_layer_store = ArchiveLayerStore::new(from);
+            let v11_layer_store = ArchiveLayerStore::new(to);
+            verify_store_with_stores(&v10_layer_store, &v11_layer_store, from, options).await
+        }
+        StoreLayout::Directory => {
+            let v10_layer_store = DirectoryLayerStore::new(from);
+            let v11_layer_store = DirectoryLayerStore::new(to);
+            verify_store_with_stores(&v10_layer_store, &v11_layer_store, from, options).await
+        }
+    }
+}
+
+async fn verify_store_with_stores<F: PersistentLayerStore, T: PersistentLayerStore>(
+    v10_layer_store: &F,
+    v11_layer_store: &T,
+    from: &str,
+    options: &ConversionOptions,
+) -> io::Result<VerifySummary> {
+    let ConversionOptions {
+        cutoff,
+        policy,
+        reverse,
+        ..
+    } = options.clone();
+    let observer = options.observer_or_default();
+    let creation_times = options.creation_times_or_default(from);
+    let v10_label_store = DirectoryLabelStore::new(from);
+
+    let reachable = find_reachable_layers(v10_layer_store, &v10_label_store, &*observer).await?;
+    let mut layers: Vec<[u32; 5]> = reachable.values().flatten().cloned().collect();
+    layers.sort();
+    layers.dedup();
+
+    let mut summary = VerifySummary::default();
+    for layer in layers {
+        summary.layers += 1;
+        let copied = !reverse && creation_times.creation_time(layer).await? > cutoff;
+        let divergences = verify_layer(v10_layer_store, v11_layer_store, layer, copied, policy, reverse).await?;
+        observer.layer_verified(layer, &divergences);
+        summary.divergences.extend(divergences.into_iter().map(|divergence| (layer, divergence)));
+    }
+    observer.verification_finished(&summary);
+
+    Ok(summary)
+}
+
+async fn verify_layer<F: PersistentLayerStore, T: PersistentLayerStore>(
+    from_store: &F,
+    to_store: &T,
+    id: [u32; 5],
+    copied: bool,
+    policy: MalformedEscapePolicy,
+    reverse: bool,
+) -> io::Result<Vec<String>> {
+    let v10_layer = from_store
+        .get_layer(id)
+        .await?
+        .expect("reachable layer should exist in the original store");
+    let v11_layer = match to_store.get_layer(id).await {
+        Ok(Some(layer)) => layer,
+        Ok(None) => return Ok(vec!["layer is missing from the converted store".to_string()]),
+        Err(e) => return Ok(vec![format!("failed to load converted layer: {e}")]),
+    };
+
+    let mut divergences = Vec::new();
+    if v10_layer.is_rollup() && !v11_layer.is_rollup() {
+        divergences.push("rollup is missing from the converted store".to_string());
+        return Ok(divergences);
+    }
+
+    let additions = expected_triples(&v10_layer, v10_layer.internal_triple_additions(), copied, policy, reverse, &mut divergences);
+    let removals = expected_triples(&v10_layer, v10_layer.internal_triple_removals(), copied, policy, reverse, &mut divergences);
+
+    compare_triples(&v11_layer, &additions, v11_layer.internal_triple_layer_addition_count(), "addition", &mut divergences, |s, p, o| {
+        v11_layer.internal_triple_addition_exists(s, p, o)
+    });
+    compare_triples(&v11_layer, &removals, v11_layer.internal_triple_layer_removal_count(), "removal", &mut divergences, |s, p, o| {
+        v11_layer.internal_triple_removal_exists(s, p, o)
+    });
+
+    Ok(divergences)
+}
+
+/// Resolve the original triples and convert their object values,
+/// collapsing the triples that become equal.
+fn expected_triples(
+    layer: &InternalLayer,
+    triples: impl Iterator<Item = IdTriple>,
+    copied: bool,
+    policy: MalformedEscapePolicy,
+    reverse: bool,
+    divergences: &mut Vec<String>,
+) -> BTreeSet<ValueTriple> {
+    let mut result = BTreeSet::new();
+    let mut errors = Vec::new();
+    for triple in triples {
+        let value_triple = match layer.id_triple_to_string(&triple) {
+            Some(value_triple) => value_triple,
+            None => {
+                divergences.push(format!("could not resolve original triple {triple:?}"));
+                continue;
+            }
+        };
+        let value_triple = match value_triple.object {
+            ObjectType::Value(ref entry) if !copied => match convert_entry(triple.object, entry.clone(), policy, reverse, &mut errors) {
+                Ok((entry, _)) => ValueTriple {
+                    object: ObjectType::Value(entry),
+                    ..value_triple
+                },
+                Err(e) => {
+                    divergences.push(format!("could not convert object of {}: {e}", display_triple(&value_triple)));
+                    continue;
+                }
+            },
+            object => ValueTriple { object, ..value_triple },
+        };
+        result.insert(value_triple);
+    }
+
+    result
+}
+
+fn compare_triples(
+    layer: &Arc<InternalLayer>,
+    expected: &BTreeSet<ValueTriple>,
+    count: usize,
+    kind: &str,
+    divergences: &mut Vec<String>,
+    exists: impl Fn(u64, u64, u64) -> bool,
+) {
+    if count != expected.len() {
+        divergences.push(format!("expected {} {kind}s but found {count}", expected.len()));
+    }
+    for triple in expected {
+        let found = layer
+            .value_triple_to_id(triple)
+            .map(|t| exists(t.subject, t.predicate, t.object))
+            .unwrap_or(false);
+        if !found {
+            divergences.push(format!("{kind} {} is missing", display_triple(triple)));
+        }
+    }
+}
+
+fn display_triple(triple: &ValueTriple) -> String {
+    let object = match &triple.object {
+        ObjectType::Node(node) => format!("<{node}>"),
+        ObjectType::Value(entry) => match entry.datatype() {
+            Datatype::LangString => format!("{:?}", entry.as_val::<LangString, String>()),
+            Datatype::String => format!("{:?}", entry.as_val::<String, String>()),
+            datatype => format!("{:?}^^{datatype:?}", entry.to_bytes()),
+        },
+    };
+
+    format!("<{}> <{}> {object}", triple.subject, triple.predicate)
+}